@@ -1,2 +1 @@
 pub mod myshell;
-pub mod string_utils;
\ No newline at end of file
@@ -3,15 +3,33 @@ extern crate nix;
 extern crate argparse;
 extern crate lazy_static;
 
-use argparse::{ArgumentParser, Store, StoreTrue};
+use argparse::{ArgumentParser, List, Store, StoreTrue};
 use std::process;
-use myshell::myshell::MyShell;
+use myshell::myshell::{
+    default_token_file, ClientConfig, Identity, MyShell, ServerConfig, DEFAULT_BIND_ADDRESS,
+    DEFAULT_SHUTDOWN_GRACE,
+};
+use std::time::Duration;
 
 fn main() {
     let mut version = false;
     let mut script = String::new();
+    let mut script_args: Vec<String> = Vec::new();
     let mut server = false;
     let mut port = String::new();
+    let mut bind = String::from(DEFAULT_BIND_ADDRESS);
+    let mut socket = String::new();
+    let mut socket_mode = String::new();
+    let mut max_sessions: usize = 0;
+    let mut idle_timeout: u64 = 0;
+    let mut shutdown_grace: u64 = DEFAULT_SHUTDOWN_GRACE.as_secs();
+    let mut connect = String::new();
+    let mut tty = false;
+    let mut token_file = default_token_file();
+    let mut tls_cert = String::new();
+    let mut tls_key = String::new();
+    let mut tls_ca = String::new();
+    let mut audit_log = String::new();
     {
         // this block limits scope of borrows by ap.refer() method
         let mut ap = ArgumentParser::new();
@@ -24,6 +42,70 @@ fn main() {
             .add_option(&["--server"], StoreTrue, "Start remote server");
         ap.refer(&mut port)
             .add_option(&["-p", "--port"], Store, "Port of started remote server");
+        ap.refer(&mut bind).add_option(
+            &["--bind"],
+            Store,
+            "IPv4 or IPv6 address of started remote server (default: 127.0.0.1)",
+        );
+        ap.refer(&mut socket)
+            .add_option(&["--socket"], Store, "Start remote server on a Unix domain socket at this path");
+        ap.refer(&mut socket_mode).add_option(
+            &["--socket-mode"],
+            Store,
+            "Octal permissions of the server socket, they decide who may connect (default: 600)",
+        );
+        ap.refer(&mut max_sessions).add_option(
+            &["--max-sessions"],
+            Store,
+            "Turn away connections while this many remote sessions run (default: no limit)",
+        );
+        ap.refer(&mut idle_timeout).add_option(
+            &["--idle-timeout"],
+            Store,
+            "Close remote sessions without input or output for this many seconds (default: never)",
+        );
+        ap.refer(&mut shutdown_grace).add_option(
+            &["--shutdown-grace"],
+            Store,
+            "Seconds running sessions get to finish when the server is stopped (default: 10)",
+        );
+        ap.refer(&mut connect).add_option(
+            &["-c", "--connect"],
+            Store,
+            "Connect to remote server at host:port, [ipv6]:port or unix:/path",
+        );
+        ap.refer(&mut tty)
+            .add_option(&["-t", "--tty"], StoreTrue, "Run the remote session on a pseudo-terminal");
+        ap.refer(&mut token_file).add_option(
+            &["--token-file"],
+            Store,
+            "File with the shared secret of the remote server (default: ~/.myshell_token)",
+        );
+        ap.refer(&mut tls_cert).add_option(
+            &["--tls-cert"],
+            Store,
+            "PEM certificate chain of the server, or of the client for certificate authentication",
+        );
+        ap.refer(&mut tls_key)
+            .add_option(&["--tls-key"], Store, "PEM private key for --tls-cert");
+        ap.refer(&mut tls_ca).add_option(
+            &["--tls-ca"],
+            Store,
+            "PEM CA certificates: the client verifies the server with them and uses TLS, \
+             the server requires client certificates signed by them",
+        );
+        ap.refer(&mut audit_log).add_option(
+            &["--audit-log"],
+            Store,
+            "Append a JSON line for every session, command and its status to this file",
+        );
+        ap.refer(&mut script_args).add_argument(
+            "script",
+            List,
+            "Script to execute and its arguments, they become $1, $2, ... in the script",
+        );
+        // everything after the script belongs to it, options too
+        ap.stop_on_first_argument(true);
         ap.parse_args_or_exit();
     }
 
@@ -31,21 +113,99 @@ fn main() {
         println!("Myshell, - bash, but worse, Rust port version 2.0.0");
         process::exit(0);
     }
+    // `-s script` keeps working, then all positional arguments go to the script
+    if script.is_empty() && !script_args.is_empty() {
+        script = script_args.remove(0);
+    }
+    let tcp_options = !port.is_empty() || bind != DEFAULT_BIND_ADDRESS;
+    let server_options = tcp_options
+        || !socket.is_empty()
+        || !socket_mode.is_empty()
+        || max_sessions > 0
+        || idle_timeout > 0
+        || shutdown_grace != DEFAULT_SHUTDOWN_GRACE.as_secs();
+    let tls_options = !tls_cert.is_empty() || !tls_key.is_empty() || !tls_ca.is_empty();
+    if tls_cert.is_empty() != tls_key.is_empty() {
+        eprintln!("myshell: --tls-cert and --tls-key must be given together");
+        process::exit(1);
+    }
+    let tls_identity = if tls_cert.is_empty() {
+        None
+    } else {
+        Some(Identity {
+            cert: tls_cert,
+            key: tls_key,
+        })
+    };
+    let tls_ca = if tls_ca.is_empty() { None } else { Some(tls_ca) };
     let mut shell = MyShell::new();
-    if !script.is_empty() {
-        if server || !port.is_empty() {
+    if !audit_log.is_empty() && connect.is_empty() {
+        if let Err(err) = shell.enable_audit(&audit_log) {
+            eprintln!("myshell: {}", err);
+            process::exit(1);
+        }
+    }
+    if !connect.is_empty() {
+        if server || server_options || !script.is_empty() || !audit_log.is_empty() {
+            eprintln!("myshell: --connect can't be used with script or server options");
+            process::exit(1);
+        }
+        if tls_identity.is_some() && tls_ca.is_none() {
+            eprintln!("myshell: a client certificate requires --tls-ca");
+            process::exit(1);
+        }
+        let mut config = ClientConfig::new(connect, token_file);
+        config.tty = tty;
+        config.tls_ca = tls_ca;
+        config.tls_identity = tls_identity;
+        process::exit(shell.start_client(config));
+    } else if !script.is_empty() {
+        if server || server_options || tls_options {
             eprintln!("myshell: Can't use script and server at the same time");
             process::exit(1);
         }
-        process::exit(shell.run_script(script));
+        process::exit(shell.run_script(script, script_args));
     } else if server {
-        if port.is_empty() {
+        let mut config = ServerConfig::new(port, bind, token_file);
+        config.max_sessions = max_sessions;
+        if idle_timeout > 0 {
+            config.idle_timeout = Some(Duration::from_secs(idle_timeout));
+        }
+        config.shutdown_grace = Duration::from_secs(shutdown_grace);
+        if tls_identity.is_none() && tls_ca.is_some() {
+            eprintln!("myshell: client certificates require --tls-cert and --tls-key");
+            process::exit(1);
+        }
+        config.tls_identity = tls_identity;
+        config.tls_client_ca = tls_ca;
+        if !socket.is_empty() {
+            if tcp_options || tls_options {
+                eprintln!("myshell: --socket can't be used with --port, --bind or TLS");
+                process::exit(1);
+            }
+            config.socket = Some(socket);
+        } else if config.port.is_empty() {
             eprintln!("Port number is required when starting server");
             process::exit(1);
+        } else if !socket_mode.is_empty() {
+            eprintln!("myshell: --socket-mode requires --socket");
+            process::exit(1);
         }
-        process::exit(shell.start_server(port));
-    } else if !port.is_empty() {
-        eprintln!("--server is required when specifying port");
+        if !socket_mode.is_empty() {
+            config.socket_mode = match u32::from_str_radix(&socket_mode, 8) {
+                Ok(mode) if mode <= 0o777 => mode,
+                _ => {
+                    eprintln!("myshell: invalid socket mode '{}': expected octal permissions like 660", socket_mode);
+                    process::exit(1);
+                }
+            };
+        }
+        process::exit(shell.start_server(config));
+    } else if tty {
+        eprintln!("--connect is required when asking for a terminal");
+        process::exit(1);
+    } else if server_options || tls_options {
+        eprintln!("--server or --connect is required when specifying server or TLS options");
         process::exit(1);
     } else {
         process::exit(shell.start_int_shell());
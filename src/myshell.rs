@@ -1,55 +1,112 @@
+mod audit;
+mod auth;
 mod mcommands;
 mod preprocessing;
 mod utils;
 mod server;
+mod client;
+mod protocol;
+mod connection;
+mod tls;
+mod lexer;
+mod parser;
+mod compound;
+mod arith;
+mod descriptors;
+mod braces;
+mod pattern;
+mod arrays;
 
-use lazy_static::lazy_static;
-use nix::libc::{strerror, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
+use nix::libc::{signal, SIGINT, SIGQUIT, SIG_DFL};
+use nix::unistd::{fork, getpid, ForkResult, Pid};
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 use std::env;
-use std::os::unix::prelude::FromRawFd;
+use std::os::unix::prelude::{CommandExt, ExitStatusExt};
 use std::path::Path;
-use std::process::{Child, Command, Stdio};
-use std::{collections::HashMap, process};
+use std::process::{Child, Command};
+use std::rc::Rc;
+use std::time::Instant;
+use std::{collections::HashMap, mem, process};
 
-use self::utils::result_pathbuf_to_string;
-
-lazy_static! {
-    pub static ref REDIRECTION_KEYS: Vec<&'static str> = vec!["2>", "&>", ">&", "<", ">"];
-    pub static ref REDIRECTIONS: HashMap<&'static str, Vec<i32>> = {
-        let mut m = HashMap::new();
-        m.insert(">", vec![STDOUT_FILENO]);
-        m.insert("2>", vec![STDERR_FILENO]);
-        m.insert("&>", vec![STDOUT_FILENO, STDERR_FILENO]);
-        m.insert("<", vec![STDIN_FILENO]);
-        m.insert(">&", vec![STDOUT_FILENO, STDERR_FILENO]);
-        m
-    };
-    pub static ref SPECIAL_SYMBOLS: Vec<char> = vec!['$', ' ', '\'', '"'];
-}
+pub use self::auth::default_token_file;
+pub use self::client::ClientConfig;
+pub use self::connection::DEFAULT_BIND_ADDRESS;
+pub use self::server::{ServerConfig, DEFAULT_SHUTDOWN_GRACE};
+pub use self::tls::Identity;
+use self::audit::{AuditLog, Field};
+use self::descriptors::Descriptors;
+use self::arrays::Array;
+use self::pattern::GlobOptions;
+use self::parser::{parse, AndOr, AndOrOp, FunctionDef, Input, LineBuffer, List, PipeSequence};
+use self::utils::{load_history, result_pathbuf_to_string, wait_for};
 
 #[derive(Clone, Debug, PartialEq)]
 enum CommandType {
     Internal,
     External,
     LocalVar,
+    Empty,
+    // compound commands and function definitions, they are not expanded to words
+    Compound,
+    Function,
+}
+
+// set by break, continue and return, the lists stop running until a loop or
+// function call takes it
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Jump {
+    Break(u32),
+    Continue(u32),
+    Return(i32),
 }
 
 pub struct MyShell {
     time_to_exit: bool,
+    // reading commands from a terminal or a client, errors don't end the shell
+    interactive: bool,
     // aliases: HashMap<String, String>,
     local_vars: HashMap<String, String>,
+    // variables that were given elements, they are not in local_vars
+    arrays: HashMap<String, Array>,
+    functions: HashMap<String, Rc<FunctionDef>>,
+    // $1, $2, ... of the script or function call
+    positional: Vec<String>,
+    // for every running function call, values that `local` variables shadow
+    local_frames: Vec<HashMap<String, Option<String>>>,
+    // $0
+    script_name: String,
+    // $$, forked copies of the shell keep it
+    shell_pid: i32,
+    // $!
+    last_background: Option<i32>,
+    // the pipe ends of <(...) and >(...) the running commands use, and the
+    // copies of the shell on their other side
+    substitutions: Vec<(i32, Pid)>,
     pub exec_path: String,
     pub last_exit_code: i32,
     internal_cmds: Vec<&'static str>,
+    audit: Option<AuditLog>,
+    jump: Option<Jump>,
+    // number of loops around the running command
+    loop_depth: u32,
+    // set with shopt
+    glob_options: GlobOptions,
 }
 
 pub struct Pipeline {
     steps: Vec<Vec<String>>,
-    ioe_descriptors: Vec<[i32; 3]>,
+    // what each step gets at the descriptor numbers it redirects
+    descriptors: Vec<Descriptors>,
     types: Vec<CommandType>,
-    subshell_comm: Vec<HashMap<usize, Vec<(usize, usize)>>>,
+    // external commands are not waited for
+    background: bool,
+}
+
+impl Default for MyShell {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MyShell {
@@ -60,7 +117,8 @@ impl MyShell {
         let exec_path = result_pathbuf_to_string(env::current_exe());
         let last_exit_code = 0;
         let internal_cmds: Vec<&'static str> = vec![
-            "merrno", "mpwd", "mcd", ".", "mecho", "mexport", "alias", "mexit",
+            "merrno", "mpwd", "mcd", ".", "mecho", "mexport", "alias", "mexit", "break", "continue",
+            "local", "return", "shift", "let", "shopt", "declare", "unset",
         ];
         // dirname()
         let exec_path: Vec<&str> = exec_path.split("/").collect();
@@ -78,44 +136,77 @@ impl MyShell {
         }
         MyShell {
             time_to_exit,
+            interactive: false,
             // aliases,
             local_vars,
+            arrays: HashMap::new(),
+            functions: HashMap::new(),
+            positional: Vec::new(),
+            local_frames: Vec::new(),
+            script_name: String::from("myshell"),
+            shell_pid: getpid().as_raw(),
+            last_background: None,
+            substitutions: Vec::new(),
             exec_path,
             last_exit_code,
             internal_cmds,
+            audit: None,
+            jump: None,
+            loop_depth: 0,
+            glob_options: GlobOptions::new(),
+        }
+    }
+
+    // every session, command line and its status is recorded from now on
+    pub fn enable_audit(&mut self, path: &str) -> Result<(), String> {
+        self.audit = Some(AuditLog::open(path)?);
+        Ok(())
+    }
+
+    fn audit(&mut self, event: &str, fields: &[(&str, Field)]) {
+        if let Some(audit) = &mut self.audit {
+            audit.record(event, fields);
         }
     }
 
     pub fn start_int_shell(&mut self) -> i32 {
         // `()` can be used when no completer is required
         let mut rl = Editor::<()>::new();
+        let history_filename = load_history(&mut rl);
+        self.audit("session_start", &[("mode", Field::Str("interactive"))]);
+        self.interactive = true;
 
-        let home_path = env::var("HOME").unwrap_or_else(|_| {
-            eprintln!("Error: HOME variable is unset");
-            process::exit(1);
-        });
-        let history_filename = home_path + "/.myshell_history";
-
-        if rl.load_history(&history_filename).is_err() {
-            std::fs::File::create(&history_filename).unwrap_or_else(|err| {
-                eprintln!("Error: could not create history file: {}", err);
-                process::exit(1);
-            });
-        }
+        let mut lines = LineBuffer::default();
         while !self.time_to_exit {
-            // pwd
-            let curdir = result_pathbuf_to_string(env::current_dir());
+            // pwd, or a continuation prompt while a command spans several lines
+            let prompt = if lines.is_empty() {
+                result_pathbuf_to_string(env::current_dir()) + " $ "
+            } else {
+                String::from("> ")
+            };
 
             // read input
-            let readline = rl.readline(&(curdir + " $ "));
+            let readline = rl.readline(&prompt);
             match readline {
                 Ok(line) => {
                     rl.add_history_entry(line.as_str());
-                    let mut line = String::from(line.trim());
-                    if line.is_empty() {
+                    let input = match lines.push(&line, self.glob_options.extglob) {
+                        Some(input) => input,
+                        None => continue,
+                    };
+                    if input.source.trim().is_empty() {
                         continue;
                     }
-                    self.last_exit_code = self.interpret_line(&mut line);
+                    self.last_exit_code = self.interpret_line(input);
+                }
+                // ^C drops an unfinished command
+                Err(ReadlineError::Interrupted) if !lines.is_empty() => {
+                    lines.clear();
+                }
+                Err(ReadlineError::Eof) if !lines.is_empty() => {
+                    // reports what is missing
+                    self.last_exit_code = self.interpret_line(lines.take());
+                    break;
                 }
                 Err(ReadlineError::Interrupted | ReadlineError::Eof) => {
                     break;
@@ -130,72 +221,134 @@ impl MyShell {
         rl.save_history(&history_filename).unwrap_or_else(|err| {
             println!("Warning: could not save history file: {}", err);
         });
+        self.audit("session_end", &[("status", Field::Int(self.last_exit_code as i64))]);
         self.last_exit_code
     }
 
-    fn interpret_line(&mut self, line: &mut str) -> i32 {
-        // First step
-        let line = MyShell::preprocess_comments(line);
-        let line = line.trim();
+    fn interpret_line(&mut self, input: Input) -> i32 {
+        let started = Instant::now();
+        let status = match &input.list {
+            Ok(list) => self.run_list(list),
+            Err(err) => {
+                eprintln!("myshell: {}", err);
+                2
+            }
+        };
+        if let Some(audit) = &mut self.audit {
+            audit.command(input.source.trim_end(), status, started.elapsed());
+        }
+        status
+    }
+
+    fn run_line(&mut self, line: &mut str) -> i32 {
+        let list = match parse(line, self.glob_options.extglob) {
+            Ok(list) => list,
+            Err(err) => {
+                eprintln!("myshell: {}", err);
+                return 2;
+            }
+        };
+        self.run_list(&list)
+    }
 
-        if line.is_empty() {
-            return 0;
+    fn run_list(&mut self, list: &List) -> i32 {
+        for item in &list.items {
+            if self.jump.is_some() || self.time_to_exit {
+                break;
+            }
+            // every element sees the status of the one before it
+            self.last_exit_code = self.run_and_or(&item.and_or, item.background);
         }
+        self.last_exit_code
+    }
 
-        // Second step
-        let second_prep_step = |line: &str| -> Result<Pipeline, String> {
-            let line = MyShell::split_command(line)?;
-            let line = MyShell::preprocess_pipeline(line)?;
-            let line = MyShell::preprocess_subshells(line)?;
-            let line = MyShell::preprocess_redirections(line)?;
-            for step in &line.steps {
-                if step.is_empty() {
-                    return Err("myshell: syntax error".to_string());
+    fn run_and_or(&mut self, and_or: &AndOr, background: bool) -> i32 {
+        let simple = and_or.rest.is_empty()
+            && and_or.first.commands.iter().all(|command| !matches!(command, parser::Command::Compound(..)));
+        if background && !simple {
+            // the whole list goes to the background, so it runs in a copy of the shell
+            return match unsafe { fork() } {
+                Ok(ForkResult::Child) => process::exit(self.run_and_or(and_or, false)),
+                Ok(ForkResult::Parent { child }) => {
+                    self.last_background = Some(child.as_raw());
+                    0
                 }
+                Err(err) => {
+                    eprintln!("myshell: fork() failed: {}", err);
+                    1
+                }
+            };
+        }
+        let mut status = self.run_pipe_sequence(&and_or.first, background);
+        for (op, sequence) in &and_or.rest {
+            if self.jump.is_some() || self.time_to_exit {
+                break;
             }
-            Ok(line)
-        };
+            self.last_exit_code = status;
+            let run = match op {
+                AndOrOp::And => status == 0,
+                AndOrOp::Or => status != 0,
+            };
+            if run {
+                status = self.run_pipe_sequence(sequence, false);
+            }
+        }
+        status
+    }
+
+    fn run_pipe_sequence(&mut self, sequence: &PipeSequence, background: bool) -> i32 {
+        // process substitutions are there until the commands using them are done
+        let substitutions = self.substitutions.len();
+        let status = self.expand_pipe_sequence(sequence, background);
+        self.close_substitutions(substitutions, !background);
+        status
+    }
 
-        let mut line = match second_prep_step(line) {
-            Ok(l) => l,
+    fn expand_pipe_sequence(&mut self, sequence: &PipeSequence, background: bool) -> i32 {
+        let mut line = match MyShell::preprocess_pipeline(sequence)
+            .and_then(|p| self.preprocess_redirections(p, sequence))
+        {
+            Ok(p) => p,
             Err(err) => {
-                // if error is parsable string then it's an errno
-                let errno_: i32 = err.parse().unwrap_or_else(|_| {
-                    eprintln!("myshell: {}", err);
-                    return 1;
-                });
-                unsafe {
-                    eprintln!("myshell: {:?}", strerror(errno_));
-                }
+                eprintln!("myshell: {}", err);
                 return 1;
             }
         };
-        
-        // Third step
-        for i in 0..line.steps.len() {
-            // TODO: add variable substitution & subshell search
-            line.steps[i] = match MyShell::insert_myshell(
-                            MyShell::expand_globs(
-                                self.substitute_vars_rem_parenth(
-                                    Ok(line.steps[i].clone()
-                            )))) 
-            {
+        line.background = background;
+
+        for (i, command) in sequence.commands.iter().enumerate() {
+            let command = match command {
+                parser::Command::Simple(command) => command,
+                // expanded when it runs
+                _ => continue,
+            };
+            // name=(...) is done right away, nothing is left to run
+            if let Some(status) = self.assign_arrays(&command.words) {
+                if status != 0 {
+                    line.descriptors.iter_mut().for_each(Descriptors::close_all);
+                    return status;
+                }
+                continue;
+            }
+            let words = self.expand_words(&command.words);
+            line.steps[i] = match MyShell::insert_myshell(self.expand_globs(words)) {
                 Ok(val) => val,
                 Err(err) => {
                     eprintln!("myshell: {}", err);
+                    line.descriptors.iter_mut().for_each(Descriptors::close_all);
                     return 1;
                 }
             }
         }
         let line = self.mark_command_types(line);
-        self.execute_pipeline(line)
+        self.execute_pipeline(line, sequence)
     }
 
-    fn execute_pipeline(&mut self, mut p: Pipeline) -> i32 {
+    fn execute_pipeline(&mut self, mut p: Pipeline, sequence: &PipeSequence) -> i32 {
         let path = match env::var("PATH") {
             Ok(val) => val,
             Err(err) => {
-                eprintln!("myshell: {}", err.to_string());
+                eprintln!("myshell: {}", err);
                 return 1;
             }
         };
@@ -203,70 +356,44 @@ impl MyShell {
         path.push("");
 
         let n_steps = p.steps.len();
-        let mut childs: Vec<Child> = Vec::new();
+        let mut childs: Vec<(usize, Child)> = Vec::new();
+        let mut forked: Vec<(usize, Pid)> = Vec::new();
         let mut statuses: Vec<i32> = vec![0; n_steps];
 
-        #[cfg(debug_assertions)]
-        {
-            println!("n_steps = {}", n_steps);
-            println!("command types: {:?}", p.types);
-            for i in 0..n_steps {
-                println!(
-                    "step: {}: {:?}; ioe descriptors: {:?}",
-                    i, p.steps[i], p.ioe_descriptors[i],
-                );
-            }
-        }
         // run all external first to make sure that write to pipe from internal later is not blocking execution
-        for step_i in 0..n_steps {
+        for (step_i, step_status) in statuses.iter_mut().enumerate() {
             if p.types[step_i] == CommandType::External {
-                let command = &mut p.steps[step_i];
-                let mut background = false;
-                if command.last().unwrap() == "&" {
-                    background = true;
-                    command.pop();
-                }
-                // TODO: Add subshell processing
+                let command = &p.steps[step_i];
                 let mut found_binary = false;
                 for &subpath in &path {
                     let bin_path = String::from(subpath) + "/" + &command[0];
                     if Path::new(&bin_path).exists() {
                         found_binary = true;
-                        let descs = &p.ioe_descriptors[step_i];
-                        let (in_, out_, err_) = unsafe {
-                            (
-                                if descs[0] != STDIN_FILENO {
-                                    Stdio::from_raw_fd(descs[0])
-                                } else {
-                                    Stdio::inherit()
-                                },
-                                if descs[1] != STDOUT_FILENO {
-                                    Stdio::from_raw_fd(descs[1])
-                                } else {
-                                    Stdio::inherit()
-                                },
-                                if descs[2] != STDERR_FILENO {
-                                    Stdio::from_raw_fd(descs[2])
-                                } else {
-                                    Stdio::inherit()
-                                },
-                            )
-                        };
-                        let child = match Command::new(bin_path)
-                            .args(&command[1..])
-                            .stdin(in_)
-                            .stdout(out_)
-                            .stderr(err_)
-                            .spawn()
+                        let mut cmd = Command::new(bin_path);
+                        cmd.args(&command[1..]);
+                        p.descriptors[step_i].attach(&mut cmd);
+                        // a remote session on a pty ignores ^C and ^\, the program must not
+                        unsafe {
+                            cmd.pre_exec(|| {
+                                signal(SIGINT, SIG_DFL);
+                                signal(SIGQUIT, SIG_DFL);
+                                Ok(())
+                            });
+                        }
+                        let child = match cmd.spawn()
                         {
                             Ok(c) => c,
                             Err(err) => {
-                                eprintln!("myshell: {}", err.to_string());
+                                eprintln!("myshell: {}", err);
                                 process::exit(1);
                             }
                         };
-                        if !background {
-                            childs.push(child);
+                        // the program has its copies of the other descriptors
+                        p.descriptors[step_i].close_all();
+                        if p.background {
+                            self.last_background = Some(child.id() as i32);
+                        } else {
+                            childs.push((step_i, child));
                         }
 
                         break;
@@ -274,41 +401,84 @@ impl MyShell {
                 }
                 if !found_binary {
                     eprintln!("myshell: command not found: {}", &command[0]);
-                    statuses[step_i] = 127;
+                    p.descriptors[step_i].close_all();
+                    *step_status = 127;
+                }
+            } else if n_steps > 1 && matches!(p.types[step_i], CommandType::Compound | CommandType::Function) {
+                // in a pipeline they run next to the others in a copy of the shell
+                let command = &sequence.commands[step_i];
+                let step = &p.steps[step_i];
+                let is_function = p.types[step_i] == CommandType::Function;
+                let forked_step = self.fork_step(&mut p.descriptors, step_i, |shell| {
+                    if is_function {
+                        shell.call_function(step)
+                    } else {
+                        shell.run_command_node(command)
+                    }
+                });
+                match forked_step {
+                    Ok(pid) => forked.push((step_i, pid)),
+                    Err(err) => {
+                        eprintln!("myshell: {}", err);
+                        *step_status = 1;
+                    }
                 }
             }
         }
         // now run all internal
-        for step_i in 0..n_steps {
-            let command = &mut p.steps[step_i];
-            // TODO: subshell
+        for (step_i, step_status) in statuses.iter_mut().enumerate() {
+            let command = &p.steps[step_i];
+            let descs = mem::take(&mut p.descriptors[step_i]);
             if p.types[step_i] == CommandType::Internal {
-                if command.last().unwrap() == "&" {
-                    command.pop();
-                }
-                let status = self.call_mcommand(command, p.ioe_descriptors[step_i]);
-                statuses[step_i] = status;
+                *step_status = self.call_mcommand(command, MyShell::standard_descriptors(descs));
             } else if p.types[step_i] == CommandType::LocalVar {
-                let status = self.set_local_variable(command, p.ioe_descriptors[step_i]);
-                statuses[step_i] = status;
+                *step_status = self.set_local_variable(command, MyShell::standard_descriptors(descs));
+            } else if p.types[step_i] == CommandType::Compound && n_steps == 1 {
+                let command = &sequence.commands[step_i];
+                *step_status = self.with_descriptors(descs, |shell| shell.run_command_node(command));
+            } else if p.types[step_i] == CommandType::Function && n_steps == 1 {
+                *step_status = self.with_descriptors(descs, |shell| shell.call_function(command));
+            } else {
+                // empty steps, and the ones that were started already
+                let mut descs = descs;
+                descs.close_all();
             }
         }
 
-        for mut child in childs {
-            child.wait().expect("Could not wait for child");
+        for (step_i, pid) in forked {
+            statuses[step_i] = wait_for(pid).unwrap_or(1);
+        }
+
+        for (step_i, mut child) in childs {
+            let status = child.wait().expect("Could not wait for child");
+            statuses[step_i] = status
+                .code()
+                .unwrap_or_else(|| 128 + status.signal().unwrap_or(0));
         }
 
         // check if everybody  finished successfully
-        for step_i in 0..n_steps {
-            if statuses[step_i] != 0 {
-                return statuses[step_i];
+        for status in statuses {
+            if status != 0 {
+                return status;
             }
         }
-        return 0;
+        0
+    }
+
+    // internal commands only write to the standard descriptors
+    fn standard_descriptors(mut descs: Descriptors) -> [i32; 3] {
+        let standard = descs.take_standard();
+        descs.close_all();
+        standard
     }
 
-    pub fn run_script(&mut self, path: String) -> i32 {
+    pub fn run_script(&mut self, path: String, args: Vec<String>) -> i32 {
+        self.audit("session_start", &[("mode", Field::Str("script")), ("script", Field::Str(&path))]);
+        self.script_name = path.clone();
+        self.positional = args;
         let command = vec![String::from("."), path];
-        return self.execute_script(&command, [0, 1, 2]);
+        let status = self.execute_script(&command, [0, 1, 2]);
+        self.audit("session_end", &[("status", Field::Int(status as i64))]);
+        status
     }
 }
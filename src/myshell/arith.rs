@@ -0,0 +1,511 @@
+use super::arrays::{split_subscript, Array};
+use super::lexer::is_name;
+use super::MyShell;
+
+// Integer arithmetic of $((...)), ((...)) and let. The expression is parsed
+// into a tree first, so && || and ?: only evaluate the side they need.
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+// longer operators first, so << is not read as two <
+const OPERATORS: [&str; 38] = [
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=", "*=", "/=", "%=",
+    "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "&", "^", "|", "!", "~", "=", "?", ":", ",", "(",
+];
+
+#[derive(Debug)]
+enum Expr {
+    Num(i64),
+    Var(String),
+    // - + ! ~
+    Unary(&'static str, Box<Expr>),
+    // ++x and --x add `delta` and give the new value, x++ and x-- the old one
+    Increment { name: String, delta: i64, prefix: bool },
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    // = and the compound assignments, with the binary operator they apply
+    Assign(String, Option<&'static str>, Box<Expr>),
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c.is_whitespace() {
+            pos += 1;
+        } else if is_word_char(c) {
+            let start = pos;
+            while pos < chars.len() && (is_word_char(chars[pos]) || (matches!(chars[pos], '#' | '@') && c.is_ascii_digit())) {
+                pos += 1;
+            }
+            // an array element, with an expression as subscript
+            if pos < chars.len() && chars[pos] == '[' && !c.is_ascii_digit() {
+                let mut nesting = 0;
+                while pos < chars.len() {
+                    nesting += match chars[pos] {
+                        '[' => 1,
+                        ']' => -1,
+                        _ => 0,
+                    };
+                    pos += 1;
+                    if nesting == 0 {
+                        break;
+                    }
+                }
+                if nesting != 0 {
+                    return Err(syntax_error(expr, &chars[start..].iter().collect::<String>()));
+                }
+            }
+            let word: String = chars[start..pos].iter().collect();
+            tokens.push(if c.is_ascii_digit() {
+                Token::Num(parse_number(&word)?)
+            } else if is_name(&word) || split_subscript(&word).is_some() {
+                Token::Name(word)
+            } else {
+                return Err(syntax_error(expr, &word));
+            });
+        } else if c == ')' {
+            tokens.push(Token::Op(")"));
+            pos += 1;
+        } else {
+            let rest: String = chars[pos..chars.len().min(pos + 3)].iter().collect();
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    pos += op.chars().count();
+                }
+                None => return Err(syntax_error(expr, &chars[pos..].iter().collect::<String>())),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// the digits of bases up to 64 are 0-9, a-z, A-Z, @ and _; up to base 36
+// upper and lower case letters are the same
+fn digit_value(c: char, base: u32) -> Option<u32> {
+    let value = match c {
+        '0'..='9' => c as u32 - '0' as u32,
+        'a'..='z' => c as u32 - 'a' as u32 + 10,
+        'A'..='Z' if base <= 36 => c as u32 - 'A' as u32 + 10,
+        'A'..='Z' => c as u32 - 'A' as u32 + 36,
+        '@' => 62,
+        '_' => 63,
+        _ => return None,
+    };
+    (value < base).then_some(value)
+}
+
+// decimal, 0x hexadecimal, 0 octal or base#digits
+fn parse_number(word: &str) -> Result<i64, String> {
+    let invalid = || format!("{}: value too great for base (error token is \"{}\")", word, word);
+    let (base, digits) = if let Some((base, digits)) = word.split_once('#') {
+        match base.parse::<u32>() {
+            Ok(base) if (2..=64).contains(&base) => (base, digits),
+            _ => return Err(format!("{}: invalid arithmetic base (error token is \"{}\")", word, word)),
+        }
+    } else if let Some(digits) = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        (16, digits)
+    } else if word.len() > 1 && word.starts_with('0') {
+        (8, &word[1..])
+    } else {
+        (10, word)
+    };
+    if digits.is_empty() {
+        return Err(invalid());
+    }
+    let mut value: i64 = 0;
+    for c in digits.chars() {
+        let digit = digit_value(c, base).ok_or_else(invalid)?;
+        value = value.wrapping_mul(base as i64).wrapping_add(digit as i64);
+    }
+    Ok(value)
+}
+
+fn syntax_error(expr: &str, token: &str) -> String {
+    format!("{}: syntax error in expression (error token is \"{}\")", expr.trim(), token.trim())
+}
+
+struct Parser<'a> {
+    expr: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+// binary operators from the loosest to the tightest binding
+const LEVELS: [&[&str]; 9] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", ">", "<=", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+];
+
+impl<'a> Parser<'a> {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn error(&self) -> String {
+        let rest = self.tokens[self.pos.min(self.tokens.len())..]
+            .iter()
+            .map(|token| match token {
+                Token::Num(n) => n.to_string(),
+                Token::Name(name) => name.clone(),
+                Token::Op(op) => op.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        if rest.is_empty() {
+            format!("{}: syntax error: operand expected", self.expr.trim())
+        } else {
+            syntax_error(self.expr, &rest)
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.peek_op() != Some(op) {
+            return Err(self.error());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    // a, b evaluates both and gives b
+    fn comma(&mut self) -> Result<Expr, String> {
+        let mut expr = self.assignment()?;
+        while self.peek_op() == Some(",") {
+            self.pos += 1;
+            expr = Expr::Binary(",", Box::new(expr), Box::new(self.assignment()?));
+        }
+        Ok(expr)
+    }
+
+    fn assignment(&mut self) -> Result<Expr, String> {
+        if let (Some(Token::Name(name)), Some(Token::Op(op))) = (self.tokens.get(self.pos), self.tokens.get(self.pos + 1)) {
+            if op.ends_with('=') && !matches!(*op, "==" | "!=" | "<=" | ">=") {
+                let name = name.clone();
+                let binary = match *op {
+                    "=" => None,
+                    op => Some(&op[..op.len() - 1]),
+                };
+                self.pos += 2;
+                let value = self.assignment()?;
+                return Ok(Expr::Assign(name, binary, Box::new(value)));
+            }
+        }
+        self.conditional()
+    }
+
+    fn conditional(&mut self) -> Result<Expr, String> {
+        let condition = self.binary(0)?;
+        if self.peek_op() != Some("?") {
+            return Ok(condition);
+        }
+        self.pos += 1;
+        let then = self.comma()?;
+        self.expect(":")?;
+        let otherwise = self.assignment()?;
+        Ok(Expr::Cond(Box::new(condition), Box::new(then), Box::new(otherwise)))
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == LEVELS.len() {
+            return self.multiplicative();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(op) = self.peek_op().filter(|op| LEVELS[level].contains(op)) {
+            self.pos += 1;
+            left = Expr::Binary(op, Box::new(left), Box::new(self.binary(level + 1)?));
+        }
+        Ok(left)
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, String> {
+        let mut left = self.power()?;
+        while let Some(op) = self.peek_op().filter(|op| matches!(*op, "*" | "/" | "%")) {
+            self.pos += 1;
+            left = Expr::Binary(op, Box::new(left), Box::new(self.power()?));
+        }
+        Ok(left)
+    }
+
+    // ** binds from the right
+    fn power(&mut self) -> Result<Expr, String> {
+        let base = self.unary()?;
+        if self.peek_op() != Some("**") {
+            return Ok(base);
+        }
+        self.pos += 1;
+        Ok(Expr::Binary("**", Box::new(base), Box::new(self.power()?)))
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.peek_op() {
+            Some(op @ ("-" | "+" | "!" | "~")) => {
+                self.pos += 1;
+                Ok(Expr::Unary(op, Box::new(self.unary()?)))
+            }
+            Some(op @ ("++" | "--")) => {
+                self.pos += 1;
+                match self.tokens.get(self.pos) {
+                    Some(Token::Name(name)) => {
+                        let name = name.clone();
+                        self.pos += 1;
+                        let delta = if op == "++" { 1 } else { -1 };
+                        Ok(Expr::Increment { name, delta, prefix: true })
+                    }
+                    _ => Err(self.error()),
+                }
+            }
+            _ => self.postfix(),
+        }
+    }
+
+    fn postfix(&mut self) -> Result<Expr, String> {
+        match self.tokens.get(self.pos).cloned() {
+            Some(Token::Num(n)) => {
+                self.pos += 1;
+                Ok(Expr::Num(n))
+            }
+            Some(Token::Name(name)) => {
+                self.pos += 1;
+                match self.peek_op() {
+                    Some(op @ ("++" | "--")) => {
+                        self.pos += 1;
+                        let delta = if op == "++" { 1 } else { -1 };
+                        Ok(Expr::Increment { name, delta, prefix: false })
+                    }
+                    _ => Ok(Expr::Var(name)),
+                }
+            }
+            Some(Token::Op("(")) => {
+                self.pos += 1;
+                let expr = self.comma()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            _ => Err(self.error()),
+        }
+    }
+}
+
+fn parse(expr: &str) -> Result<Option<Expr>, String> {
+    let tokens = tokenize(expr)?;
+    // an empty expression is 0
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+    let mut parser = Parser { expr, tokens, pos: 0 };
+    let tree = parser.comma()?;
+    if parser.pos < parser.tokens.len() {
+        return Err(parser.error());
+    }
+    Ok(Some(tree))
+}
+
+// variables holding expressions are evaluated in turn, up to this depth
+const MAX_DEPTH: usize = 64;
+
+impl MyShell {
+    pub fn eval_arith(&mut self, expr: &str) -> Result<i64, String> {
+        self.eval_arith_at(expr, 0)
+    }
+
+    fn eval_arith_at(&mut self, expr: &str, depth: usize) -> Result<i64, String> {
+        if depth > MAX_DEPTH {
+            return Err(format!("{}: expression recursion level exceeded", expr.trim()));
+        }
+        match parse(expr)? {
+            Some(tree) => self.eval_expr(expr, &tree, depth),
+            None => Ok(0),
+        }
+    }
+
+    fn eval_expr(&mut self, source: &str, expr: &Expr, depth: usize) -> Result<i64, String> {
+        Ok(match expr {
+            Expr::Num(n) => *n,
+            Expr::Var(name) => self.arith_variable(name, depth)?,
+            Expr::Unary(op, operand) => {
+                let value = self.eval_expr(source, operand, depth)?;
+                match *op {
+                    "-" => value.wrapping_neg(),
+                    "!" => (value == 0) as i64,
+                    "~" => !value,
+                    _ => value,
+                }
+            }
+            Expr::Increment { name, delta, prefix } => {
+                let name = self.arith_target(name, depth)?;
+                let old = self.arith_variable(&name, depth)?;
+                let new = old.wrapping_add(*delta);
+                self.assign(&format!("{}={}", name, new))?;
+                if *prefix {
+                    new
+                } else {
+                    old
+                }
+            }
+            Expr::Binary("&&", left, right) => {
+                (self.eval_expr(source, left, depth)? != 0 && self.eval_expr(source, right, depth)? != 0) as i64
+            }
+            Expr::Binary("||", left, right) => {
+                (self.eval_expr(source, left, depth)? != 0 || self.eval_expr(source, right, depth)? != 0) as i64
+            }
+            Expr::Binary(op, left, right) => {
+                let left = self.eval_expr(source, left, depth)?;
+                let right = self.eval_expr(source, right, depth)?;
+                apply(source, op, left, right)?
+            }
+            Expr::Assign(name, op, value) => {
+                let name = self.arith_target(name, depth)?;
+                let mut value = self.eval_expr(source, value, depth)?;
+                if let Some(op) = op {
+                    value = apply(source, op, self.arith_variable(&name, depth)?, value)?;
+                }
+                self.assign(&format!("{}={}", name, value))?;
+                value
+            }
+            Expr::Cond(condition, then, otherwise) => {
+                if self.eval_expr(source, condition, depth)? != 0 {
+                    self.eval_expr(source, then, depth)?
+                } else {
+                    self.eval_expr(source, otherwise, depth)?
+                }
+            }
+        })
+    }
+
+    // the variable or element an assignment changes, an index is evaluated
+    // only once for reading and writing it
+    fn arith_target(&mut self, name: &str, depth: usize) -> Result<String, String> {
+        match split_subscript(name) {
+            Some((array, subscript)) if !matches!(self.arrays.get(array), Some(Array::Assoc(_))) => {
+                Ok(format!("{}[{}]", array, self.eval_arith_at(subscript, depth + 1)?))
+            }
+            _ => Ok(name.to_string()),
+        }
+    }
+
+    // unset and empty variables are 0
+    fn arith_variable(&mut self, name: &str, depth: usize) -> Result<i64, String> {
+        let value = match split_subscript(name) {
+            Some((array, subscript)) => self.element_at(array, subscript)?.unwrap_or_default(),
+            None => self.variable(name),
+        };
+        let value = value.trim();
+        match value.parse() {
+            Ok(n) => Ok(n),
+            Err(_) => self.eval_arith_at(value, depth + 1),
+        }
+    }
+}
+
+fn apply(source: &str, op: &str, left: i64, right: i64) -> Result<i64, String> {
+    Ok(match op {
+        "+" => left.wrapping_add(right),
+        "-" => left.wrapping_sub(right),
+        "*" => left.wrapping_mul(right),
+        "/" | "%" if right == 0 => return Err(format!("{}: division by 0", source.trim())),
+        "/" => left.wrapping_div(right),
+        "%" => left.wrapping_rem(right),
+        "**" if right < 0 => return Err(format!("{}: exponent less than 0", source.trim())),
+        "**" => left.wrapping_pow(right.min(u32::MAX as i64) as u32),
+        "<<" => left.wrapping_shl(right as u32),
+        ">>" => left.wrapping_shr(right as u32),
+        "<" => (left < right) as i64,
+        ">" => (left > right) as i64,
+        "<=" => (left <= right) as i64,
+        ">=" => (left >= right) as i64,
+        "==" => (left == right) as i64,
+        "!=" => (left != right) as i64,
+        "&" => left & right,
+        "^" => left ^ right,
+        "|" => left | right,
+        // the comma operator
+        _ => right,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expr: &str) -> Result<i64, String> {
+        MyShell::new().eval_arith(expr)
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("2 ** 3 ** 2"), Ok(512));
+        assert_eq!(eval("-2 ** 2"), Ok(4));
+        assert_eq!(eval("1 << 2 + 1"), Ok(8));
+        assert_eq!(eval("1 | 2 ^ 3 & 1"), Ok(3));
+        assert_eq!(eval("1 < 2 == 1"), Ok(1));
+        assert_eq!(eval("0 || 1 && 0"), Ok(0));
+        assert_eq!(eval("1 ? 2 : 0 ? 3 : 4"), Ok(2));
+        assert_eq!(eval("x = 2, x *= 3, x + 1"), Ok(7));
+        assert_eq!(eval("7 % 3 * 4 / 2"), Ok(2));
+    }
+
+    #[test]
+    fn short_circuit() {
+        let mut shell = MyShell::new();
+        assert_eq!(shell.eval_arith("0 && (x = 1)"), Ok(0));
+        assert_eq!(shell.eval_arith("1 || (x = 1)"), Ok(1));
+        assert_eq!(shell.eval_arith("x"), Ok(0));
+    }
+
+    #[test]
+    fn overflow_wraps() {
+        assert_eq!(eval("9223372036854775807 + 1"), Ok(i64::MIN));
+        assert_eq!(eval("-9223372036854775807 - 2"), Ok(i64::MAX));
+        assert_eq!(eval("2 ** 64"), Ok(0));
+        assert_eq!(eval("(-9223372036854775807 - 1) / -1"), Ok(i64::MIN));
+        assert!(eval("1 / 0").is_err());
+        assert!(eval("2 ** -1").is_err());
+    }
+
+    #[test]
+    fn bases() {
+        assert_eq!(eval("0x1f + 010"), Ok(39));
+        assert_eq!(eval("2#101"), Ok(5));
+        assert_eq!(eval("36#Z"), Ok(35));
+        assert_eq!(eval("36#z"), Ok(35));
+        assert_eq!(eval("62#Z"), Ok(61));
+        assert_eq!(eval("64#@"), Ok(62));
+        assert_eq!(eval("64#__"), Ok(4095));
+        assert!(eval("65#1").is_err());
+        assert!(eval("1#1").is_err());
+        assert!(eval("2#2").is_err());
+        assert!(eval("08").is_err());
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert!(eval("1 +").is_err());
+        assert!(eval("(1").is_err());
+        assert!(eval("1 2").is_err());
+        assert_eq!(eval(""), Ok(0));
+    }
+}
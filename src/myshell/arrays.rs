@@ -0,0 +1,415 @@
+use super::lexer::{is_name, Word, WordPart};
+use super::MyShell;
+
+use std::collections::BTreeMap;
+
+// The elements of an array variable. Indexed arrays may have gaps, their
+// elements come in the order of the indexes, the ones of associative arrays in
+// the order of their keys.
+#[derive(Clone, Debug)]
+pub enum Array {
+    Indexed(BTreeMap<usize, String>),
+    Assoc(BTreeMap<String, String>),
+}
+
+enum Key {
+    Index(usize),
+    Name(String),
+}
+
+impl Array {
+    fn get(&self, key: &Key) -> Option<&String> {
+        match (self, key) {
+            (Array::Indexed(elements), Key::Index(index)) => elements.get(index),
+            (Array::Assoc(elements), Key::Name(name)) => elements.get(name),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, key: Key, value: String) {
+        match (self, key) {
+            (Array::Indexed(elements), Key::Index(index)) => {
+                elements.insert(index, value);
+            }
+            (Array::Assoc(elements), Key::Name(name)) => {
+                elements.insert(name, value);
+            }
+            _ => {}
+        }
+    }
+
+    fn remove(&mut self, key: &Key) {
+        match (self, key) {
+            (Array::Indexed(elements), Key::Index(index)) => {
+                elements.remove(index);
+            }
+            (Array::Assoc(elements), Key::Name(name)) => {
+                elements.remove(name);
+            }
+            _ => {}
+        }
+    }
+
+    // where `x+=(...)` and `x+=value` without a subscript go
+    fn first_key(&self) -> Key {
+        match self {
+            Array::Indexed(_) => Key::Index(0),
+            Array::Assoc(_) => Key::Name("0".to_string()),
+        }
+    }
+
+    fn next_index(&self) -> usize {
+        match self {
+            Array::Indexed(elements) => elements.keys().next_back().map_or(0, |last| last + 1),
+            Array::Assoc(_) => 0,
+        }
+    }
+}
+
+// name[subscript] split into its name and subscript
+pub fn split_subscript(text: &str) -> Option<(&str, &str)> {
+    let (name, rest) = text.split_once('[')?;
+    let subscript = rest.strip_suffix(']')?;
+    if is_name(name) {
+        Some((name, subscript))
+    } else {
+        None
+    }
+}
+
+impl MyShell {
+    // name=value, name+=value, name[subscript]=value or name[subscript]+=value,
+    // already expanded
+    pub fn assign(&mut self, text: &str) -> Result<(), String> {
+        let (target, value) = match text.split_once('=') {
+            Some(assignment) => assignment,
+            None => return Err("syntax error".to_string()),
+        };
+        let (target, append) = match target.strip_suffix('+') {
+            Some(target) => (target, true),
+            None => (target, false),
+        };
+        let (name, subscript) = match split_subscript(target) {
+            Some((name, subscript)) => (name, Some(subscript)),
+            None if is_name(target) => (target, None),
+            None => return Err(format!("`{}': not a valid identifier", target)),
+        };
+        if subscript.is_none() && !self.arrays.contains_key(name) {
+            let value = match append {
+                true => self.variable(name) + value,
+                false => value.to_string(),
+            };
+            self.local_vars.insert(name.to_string(), value);
+            return Ok(());
+        }
+        self.make_array(name);
+        let key = match subscript {
+            Some(subscript) => self.key(name, subscript)?,
+            None => self.arrays[name].first_key(),
+        };
+        let array = self.arrays.get_mut(name).unwrap();
+        let value = match (append, array.get(&key)) {
+            (true, Some(previous)) => previous.clone() + value,
+            _ => value.to_string(),
+        };
+        array.insert(key, value);
+        Ok(())
+    }
+
+    // Runs a lone name=(...), or declare with such arguments, which are assigned
+    // after the other arguments are declared. None without array assignments.
+    pub fn assign_arrays(&mut self, words: &[Word]) -> Option<i32> {
+        let is_array = |word: &Word| matches!(word.parts.last(), Some(WordPart::Array(_)));
+        if !words.iter().any(is_array) {
+            return None;
+        }
+        let (arrays, others): (Vec<&Word>, Vec<&Word>) = words.iter().partition(|word| is_array(word));
+        let mut status = 0;
+        if words.len() > 1 {
+            if others[0].literal().as_deref() != Some("declare") {
+                eprintln!("myshell: {}: cannot use an array assignment here", arrays[0].source());
+                return Some(1);
+            }
+            let mut command = Vec::new();
+            for word in others {
+                match self.expand_word(word, true) {
+                    Ok(fields) => command.extend(fields),
+                    Err(err) => {
+                        eprintln!("myshell: {}", err);
+                        return Some(1);
+                    }
+                }
+            }
+            for word in &arrays {
+                if let Some(WordPart::Lit(target)) = word.parts.first() {
+                    command.push(target.trim_end_matches('=').trim_end_matches('+').to_string());
+                }
+            }
+            status = self.declare(&command, [0, 1, 2]);
+        }
+        for word in arrays {
+            if let Err(err) = self.assign_array(word) {
+                eprintln!("myshell: {}", err);
+                status = 1;
+            }
+        }
+        Some(status)
+    }
+
+    // name=(...) or name+=(...), whose elements are words or [key]=value
+    pub fn assign_array(&mut self, word: &Word) -> Result<(), String> {
+        let (target, elements) = match word.parts.as_slice() {
+            [WordPart::Lit(target), WordPart::Array(elements)] => (target.trim_end_matches('='), elements),
+            _ => return Err(format!("{}: cannot use an array assignment here", word.source())),
+        };
+        let (name, append) = match target.strip_suffix('+') {
+            Some(name) => (name, true),
+            None => (target, false),
+        };
+        if !append {
+            let array = match self.arrays.get(name) {
+                Some(Array::Assoc(_)) => Array::Assoc(BTreeMap::new()),
+                _ => Array::Indexed(BTreeMap::new()),
+            };
+            self.arrays.insert(name.to_string(), array);
+        }
+        self.make_array(name);
+        for element in elements {
+            let keyed = matches!(element.parts.first(), Some(WordPart::Lit(s)) if s.starts_with('['))
+                && element.parts.iter().any(|part| matches!(part, WordPart::Lit(s) if s.contains("]=")));
+            if keyed {
+                let text = self.expand_string(element)?;
+                let (key, value) = text[1..].split_once("]=").unwrap_or_default();
+                let key = self.key(name, key)?;
+                self.arrays.get_mut(name).unwrap().insert(key, value.to_string());
+                continue;
+            }
+            // other elements are split and globbed like the arguments of a command
+            let words = self.expand_words(std::slice::from_ref(element))?;
+            for value in self.glob_all(words)? {
+                let array = self.arrays.get_mut(name).unwrap();
+                match array {
+                    Array::Indexed(_) => {
+                        let index = array.next_index();
+                        array.insert(Key::Index(index), value);
+                    }
+                    Array::Assoc(_) => return Err(format!("{}: {}: must use subscript when assigning associative array", name, value)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // declare -a or -A, an existing indexed array can't become associative
+    pub fn declare_array(&mut self, name: &str, assoc: bool) -> Result<(), String> {
+        match self.arrays.get(name) {
+            Some(Array::Indexed(_)) if assoc => Err(format!("{}: cannot convert indexed to associative array", name)),
+            Some(Array::Assoc(_)) if !assoc => Err(format!("{}: cannot convert associative to indexed array", name)),
+            Some(_) => Ok(()),
+            None if assoc => {
+                let mut array = Array::Assoc(BTreeMap::new());
+                if let Some(value) = self.local_vars.remove(name) {
+                    array.insert(Key::Name("0".to_string()), value);
+                }
+                self.arrays.insert(name.to_string(), array);
+                Ok(())
+            }
+            None => {
+                self.make_array(name);
+                Ok(())
+            }
+        }
+    }
+
+    // a scalar becomes the first element of an indexed array
+    fn make_array(&mut self, name: &str) {
+        if self.arrays.contains_key(name) {
+            return;
+        }
+        let mut elements = BTreeMap::new();
+        if let Some(value) = self.local_vars.remove(name) {
+            elements.insert(0, value);
+        }
+        self.arrays.insert(name.to_string(), Array::Indexed(elements));
+    }
+
+    // The subscript of an associative array is a key, the one of any other
+    // variable an arithmetic expression. Negative indexes count from the end.
+    fn key(&mut self, name: &str, subscript: &str) -> Result<Key, String> {
+        if let Some(Array::Assoc(_)) = self.arrays.get(name) {
+            return Ok(Key::Name(subscript.to_string()));
+        }
+        let index = self.eval_arith(subscript)?;
+        if index >= 0 {
+            return Ok(Key::Index(index as usize));
+        }
+        let end = self.arrays.get(name).map_or(1, Array::next_index) as i64;
+        match end + index {
+            index if index >= 0 => Ok(Key::Index(index as usize)),
+            _ => Err(format!("{}[{}]: bad array subscript", name, subscript)),
+        }
+    }
+
+    // ${name[subscript]}, a scalar is an array of one element
+    pub fn element(&mut self, name: &str, subscript: &Word) -> Result<Option<String>, String> {
+        let subscript = self.expand_string(subscript)?;
+        self.element_at(name, &subscript)
+    }
+
+    // the element at an expanded subscript
+    pub fn element_at(&mut self, name: &str, subscript: &str) -> Result<Option<String>, String> {
+        let key = self.key(name, subscript)?;
+        match (self.arrays.get(name), key) {
+            (Some(array), key) => Ok(array.get(&key).cloned()),
+            (None, Key::Index(0)) if self.is_set(name) => Ok(Some(self.variable(name))),
+            (None, _) => Ok(None),
+        }
+    }
+
+    // ${name[@]}
+    pub fn elements(&self, name: &str) -> Vec<String> {
+        match self.arrays.get(name) {
+            Some(Array::Indexed(elements)) => elements.values().cloned().collect(),
+            Some(Array::Assoc(elements)) => elements.values().cloned().collect(),
+            None if self.is_set(name) => vec![self.variable(name)],
+            None => Vec::new(),
+        }
+    }
+
+    // ${!name[@]}
+    pub fn keys(&self, name: &str) -> Vec<String> {
+        match self.arrays.get(name) {
+            Some(Array::Indexed(elements)) => elements.keys().map(|index| index.to_string()).collect(),
+            Some(Array::Assoc(elements)) => elements.keys().cloned().collect(),
+            None if self.is_set(name) => vec!["0".to_string()],
+            None => Vec::new(),
+        }
+    }
+
+    // the value of $name for an array
+    pub fn first_element(&self, name: &str) -> Option<String> {
+        let array = self.arrays.get(name)?;
+        array.get(&array.first_key()).cloned()
+    }
+
+    // unset name[subscript]
+    pub fn unset_element(&mut self, name: &str, subscript: &str) -> Result<(), String> {
+        if !self.arrays.contains_key(name) {
+            if let Key::Index(0) = self.key(name, subscript)? {
+                self.local_vars.remove(name);
+            }
+            return Ok(());
+        }
+        let key = self.key(name, subscript)?;
+        self.arrays.get_mut(name).unwrap().remove(&key);
+        Ok(())
+    }
+
+    // declare -p name
+    pub fn describe_variable(&self, name: &str) -> Option<String> {
+        let quote = |value: &str| format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""));
+        let (flag, elements): (&str, Vec<String>) = match self.arrays.get(name) {
+            Some(Array::Indexed(elements)) => {
+                ("-a", elements.iter().map(|(index, value)| format!("[{}]={}", index, quote(value))).collect())
+            }
+            Some(Array::Assoc(elements)) => {
+                ("-A", elements.iter().map(|(key, value)| format!("[{}]={}", key, quote(value))).collect())
+            }
+            None => {
+                let value = self.local_vars.get(name)?;
+                return Some(format!("declare -- {}={}", name, quote(value)));
+            }
+        };
+        Some(format!("declare {} {}=({})", flag, name, elements.join(" ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::lexer::{tokenize, Token};
+    use super::*;
+
+    // runs name=(...)
+    fn assign_array(shell: &mut MyShell, source: &str) -> Result<(), String> {
+        match tokenize(source, false).unwrap().as_slice() {
+            [Token::Word(word)] => shell.assign_array(word),
+            tokens => panic!("not a single word: {:?}", tokens),
+        }
+    }
+
+    fn element(shell: &mut MyShell, name: &str, subscript: &str) -> Option<String> {
+        shell.element_at(name, subscript).unwrap()
+    }
+
+    #[test]
+    fn negative_subscripts() {
+        let mut shell = MyShell::new();
+        assign_array(&mut shell, "a=(x y z)").unwrap();
+        assert_eq!(element(&mut shell, "a", "-1").as_deref(), Some("z"));
+        assert_eq!(element(&mut shell, "a", "-3").as_deref(), Some("x"));
+        assert!(shell.element_at("a", "-4").is_err());
+        shell.assign("a[-1]=w").unwrap();
+        assert_eq!(shell.elements("a"), ["x", "y", "w"]);
+        shell.unset_element("a", "-2").unwrap();
+        assert_eq!(shell.keys("a"), ["0", "2"]);
+        // with gaps the end is after the last index
+        assert_eq!(element(&mut shell, "a", "-2"), None);
+    }
+
+    #[test]
+    fn appending() {
+        let mut shell = MyShell::new();
+        assign_array(&mut shell, "a=(1 2)").unwrap();
+        assign_array(&mut shell, "a+=(3 [7]=4 5)").unwrap();
+        assert_eq!(shell.keys("a"), ["0", "1", "2", "7", "8"]);
+        shell.assign("a+=x").unwrap();
+        shell.assign("a[1]+=y").unwrap();
+        assert_eq!(shell.elements("a"), ["1x", "2y", "3", "4", "5"]);
+        shell.assign("s=ab").unwrap();
+        shell.assign("s+=cd").unwrap();
+        assert_eq!(shell.variable("s"), "abcd");
+        // a scalar becomes the first element
+        assign_array(&mut shell, "s+=(ef)").unwrap();
+        assert_eq!(shell.elements("s"), ["abcd", "ef"]);
+    }
+
+    #[test]
+    fn associative_arrays() {
+        let mut shell = MyShell::new();
+        shell.declare_array("h", true).unwrap();
+        assign_array(&mut shell, "h=([b]=2 [a]=1)").unwrap();
+        shell.assign("h[1+1]=3").unwrap();
+        assert_eq!(shell.keys("h"), ["1+1", "a", "b"]);
+        assert_eq!(element(&mut shell, "h", "a").as_deref(), Some("1"));
+        assert!(assign_array(&mut shell, "h+=(c)").is_err());
+        assert_eq!(shell.describe_variable("h").unwrap(), "declare -A h=([1+1]=\"3\" [a]=\"1\" [b]=\"2\")");
+    }
+
+    #[test]
+    fn conversions() {
+        let mut shell = MyShell::new();
+        assign_array(&mut shell, "a=(1)").unwrap();
+        assert!(shell.declare_array("a", true).is_err());
+        assert!(shell.declare_array("a", false).is_ok());
+        shell.declare_array("h", true).unwrap();
+        assert!(shell.declare_array("h", false).is_err());
+        // a scalar keeps its value as element 0 of either kind
+        shell.assign("s=v").unwrap();
+        shell.declare_array("s", true).unwrap();
+        assert_eq!(element(&mut shell, "s", "0").as_deref(), Some("v"));
+        shell.assign("t=w").unwrap();
+        shell.declare_array("t", false).unwrap();
+        assert_eq!(shell.describe_variable("t").unwrap(), "declare -a t=([0]=\"w\")");
+    }
+
+    #[test]
+    fn arithmetic_on_elements() {
+        let mut shell = MyShell::new();
+        assign_array(&mut shell, "a=(10 20 30)").unwrap();
+        assert_eq!(shell.eval_arith("a[1] + a[-1]"), Ok(50));
+        assert_eq!(shell.eval_arith("i = 0, a[i++] += 5, i"), Ok(1));
+        assert_eq!(shell.elements("a"), ["15", "20", "30"]);
+        assert_eq!(shell.eval_arith("a = 7"), Ok(7));
+        assert_eq!(shell.variable("a"), "7");
+    }
+}
@@ -0,0 +1,132 @@
+use super::descriptors::private_file;
+
+use nix::unistd::{getpid, getuid, User};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub enum Field<'a> {
+    Str(&'a str),
+    Int(i64),
+}
+
+// Append-only trail of JSON objects, one per line. Every record carries the time,
+// the event name, the session it belongs to and the user running myshell.
+// Forked server processes share the file, O_APPEND keeps their lines whole.
+pub struct AuditLog {
+    file: File,
+    path: String,
+    user: String,
+    // pid of the process serving the connection, or of the interactive shell
+    session: i32,
+    // where a remote session comes from
+    peer: Option<String>,
+}
+
+impl AuditLog {
+    pub fn open(path: &str) -> Result<AuditLog, String> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|err| format!("audit log {}: {}", path, err))?;
+        Ok(AuditLog {
+            file: private_file(file),
+            path: path.to_string(),
+            user: User::from_uid(getuid())
+                .ok()
+                .flatten()
+                .map(|user| user.name)
+                .unwrap_or_default(),
+            session: getpid().as_raw(),
+            peer: None,
+        })
+    }
+
+    // called in the process serving a new connection
+    pub fn start_session(&mut self, peer: &str) {
+        self.session = getpid().as_raw();
+        self.peer = Some(peer.to_string());
+    }
+
+    pub fn record(&mut self, event: &str, fields: &[(&str, Field)]) {
+        let mut line = String::from("{");
+        line += &format!("\"time\":{}", json_string(&timestamp(SystemTime::now())));
+        line += &format!(",\"event\":{}", json_string(event));
+        line += &format!(",\"session\":{}", self.session);
+        line += &format!(",\"uid\":{},\"user\":{}", getuid(), json_string(&self.user));
+        if let Some(peer) = &self.peer {
+            line += &format!(",\"peer\":{}", json_string(peer));
+        }
+        for (name, value) in fields {
+            let value = match value {
+                Field::Str(s) => json_string(s),
+                Field::Int(n) => n.to_string(),
+            };
+            line += &format!(",{}:{}", json_string(name), value);
+        }
+        line += "}\n";
+        // a single write, so records of concurrent sessions never interleave
+        if let Err(err) = self.file.write_all(line.as_bytes()) {
+            eprintln!("myshell: audit log {}: {}", self.path, err);
+        }
+    }
+
+    pub fn command(&mut self, line: &str, status: i32, duration: Duration) {
+        self.record(
+            "command",
+            &[
+                ("line", Field::Str(line)),
+                ("status", Field::Int(status as i64)),
+                ("duration_ms", Field::Int(duration.as_millis() as i64)),
+            ],
+        );
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\r' => out += "\\r",
+            '\t' => out += "\\t",
+            c if (c as u32) < 0x20 || c == '\u{7f}' => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// RFC 3339 in UTC with milliseconds
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() as i64;
+    let (days, day_secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // civil date from days since 1970-01-01, Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        day_secs / 3600,
+        day_secs % 3600 / 60,
+        day_secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
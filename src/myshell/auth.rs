@@ -0,0 +1,118 @@
+use nix::errno::Errno;
+use nix::unistd::{read, write};
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::time::{Duration, Instant};
+
+const TOKEN_BYTES: usize = 32;
+// a peer with this many failed attempts within the window is turned away
+const MAX_AUTH_FAILURES: usize = 5;
+const AUTH_FAILURE_WINDOW: Duration = Duration::from_secs(60);
+// every failed attempt costs the client this long
+pub const AUTH_FAILURE_DELAY: Duration = Duration::from_secs(1);
+
+pub fn default_token_file() -> String {
+    let home_path = env::var("HOME").unwrap_or_else(|_| String::from("."));
+    home_path + "/.myshell_token"
+}
+
+// reads the shared secret, refusing files other users can read
+pub fn read_token_file(path: &str) -> Result<Vec<u8>, String> {
+    let mut file = File::open(path).map_err(|err| format!("{}: {}", path, err))?;
+    let mode = file.metadata().map_err(|err| format!("{}: {}", path, err))?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(format!("{}: token file must not be accessible by other users", path));
+    }
+    let mut token = String::new();
+    file.read_to_string(&mut token).map_err(|err| format!("{}: {}", path, err))?;
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(format!("{}: token file is empty", path));
+    }
+    Ok(token.as_bytes().to_vec())
+}
+
+// the server generates a random token on first start
+pub fn load_or_create_token(path: &str) -> Result<Vec<u8>, String> {
+    if fs::metadata(path).is_ok() {
+        return read_token_file(path);
+    }
+    let mut random = [0u8; TOKEN_BYTES];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut random))
+        .map_err(|err| format!("could not generate token: {}", err))?;
+    let token: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|err| format!("{}: {}", path, err))?;
+    writeln!(file, "{}", token).map_err(|err| format!("{}: {}", path, err))?;
+    Ok(token.into_bytes())
+}
+
+// compares in constant time, so the response time does not leak the token
+pub fn tokens_match(presented: &[u8], expected: &[u8]) -> bool {
+    if presented.len() != expected.len() {
+        return false;
+    }
+    presented.iter().zip(expected).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+// session processes report failed attempts over a pipe, the server process
+// counts them per peer address
+pub fn report_auth_failure(report_fd: i32, peer: IpAddr) {
+    // one short write to a pipe is atomic, so reports never interleave
+    write(report_fd, (peer.to_string() + "\n").as_bytes()).unwrap_or(0);
+}
+
+pub struct AuthLimiter {
+    report_fd: i32,
+    pending: String,
+    failures: HashMap<IpAddr, Vec<Instant>>,
+}
+
+impl AuthLimiter {
+    // `report_fd` is the non-blocking read end of the report pipe
+    pub fn new(report_fd: i32) -> AuthLimiter {
+        AuthLimiter {
+            report_fd,
+            pending: String::new(),
+            failures: HashMap::new(),
+        }
+    }
+
+    pub fn collect_reports(&mut self) {
+        let mut chunk = [0u8; 1024];
+        loop {
+            match read(self.report_fd, &mut chunk) {
+                Ok(0) | Err(Errno::EAGAIN) => break,
+                Ok(n) => self.pending += &String::from_utf8_lossy(&chunk[..n]),
+                Err(Errno::EINTR) => continue,
+                Err(_) => break,
+            }
+        }
+        while let Some(idx) = self.pending.find('\n') {
+            let line: String = self.pending.drain(..=idx).collect();
+            if let Ok(peer) = line.trim().parse::<IpAddr>() {
+                self.failures.entry(peer).or_default().push(Instant::now());
+            }
+        }
+        self.failures.retain(|_, attempts| {
+            attempts.retain(|at| at.elapsed() < AUTH_FAILURE_WINDOW);
+            !attempts.is_empty()
+        });
+    }
+
+    pub fn is_blocked(&self, peer: &IpAddr) -> bool {
+        self.failures
+            .get(peer)
+            .is_some_and(|attempts| attempts.len() >= MAX_AUTH_FAILURES)
+    }
+}
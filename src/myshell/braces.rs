@@ -0,0 +1,215 @@
+use super::lexer::{Word, WordPart};
+
+// Brace expansion comes before all other expansions and only looks at
+// unquoted text, so the word is taken apart into its unquoted characters
+// and the other parts, which are carried along as they are.
+
+#[derive(Clone, Debug)]
+enum Item {
+    Char(char),
+    Part(WordPart),
+}
+
+// a{b,c}d gives abd and acd, x{1..3} gives x1, x2 and x3
+pub fn expand_braces(word: &Word) -> Vec<Word> {
+    let mut items = Vec::new();
+    for part in &word.parts {
+        match part {
+            WordPart::Lit(s) => items.extend(s.chars().map(Item::Char)),
+            part => items.push(Item::Part(part.clone())),
+        }
+    }
+    if !items.iter().any(|item| matches!(item, Item::Char('{'))) {
+        return vec![word.clone()];
+    }
+    expand(&items).into_iter().map(|items| to_word(&items)).collect()
+}
+
+fn to_word(items: &[Item]) -> Word {
+    let mut word = Word::default();
+    for item in items {
+        match (item, word.parts.last_mut()) {
+            (Item::Char(c), Some(WordPart::Lit(s))) => s.push(*c),
+            (Item::Char(c), _) => word.parts.push(WordPart::Lit(c.to_string())),
+            (Item::Part(part), _) => word.parts.push(part.clone()),
+        }
+    }
+    word
+}
+
+fn expand(items: &[Item]) -> Vec<Vec<Item>> {
+    let mut start = 0;
+    while let Some(open) = position(items, '{', start) {
+        // braces without a comma or a sequence stay as they are
+        if let Some(close) = matching_brace(items, open) {
+            if let Some(alternatives) = alternatives(&items[open + 1..close]) {
+                let mut result = Vec::new();
+                for alternative in alternatives {
+                    let mut word = items[..open].to_vec();
+                    word.extend(alternative);
+                    word.extend_from_slice(&items[close + 1..]);
+                    // nested braces and the ones further on
+                    result.extend(expand(&word));
+                }
+                return result;
+            }
+        }
+        start = open + 1;
+    }
+    vec![items.to_vec()]
+}
+
+fn position(items: &[Item], c: char, start: usize) -> Option<usize> {
+    items[start..]
+        .iter()
+        .position(|item| matches!(item, Item::Char(d) if *d == c))
+        .map(|i| i + start)
+}
+
+fn matching_brace(items: &[Item], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, item) in items.iter().enumerate().skip(open) {
+        match item {
+            Item::Char('{') => depth += 1,
+            Item::Char('}') => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// what is between the braces, split on commas outside nested braces, or a sequence
+fn alternatives(inner: &[Item]) -> Option<Vec<Vec<Item>>> {
+    let mut alternatives = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, item) in inner.iter().enumerate() {
+        match item {
+            Item::Char('{') => depth += 1,
+            Item::Char('}') => depth -= 1,
+            Item::Char(',') if depth == 0 => {
+                alternatives.push(inner[start..i].to_vec());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if !alternatives.is_empty() {
+        alternatives.push(inner[start..].to_vec());
+        return Some(alternatives);
+    }
+    let mut text = String::new();
+    for item in inner {
+        match item {
+            Item::Char(c) => text.push(*c),
+            Item::Part(_) => return None,
+        }
+    }
+    let values = sequence(&text)?;
+    Some(values.into_iter().map(|value| value.chars().map(Item::Char).collect()).collect())
+}
+
+// first..last or first..last..step, of integers or of single letters
+fn sequence(text: &str) -> Option<Vec<String>> {
+    let bounds: Vec<&str> = text.split("..").collect();
+    let (first, last, step) = match bounds.as_slice() {
+        [first, last] => (*first, *last, 1),
+        [first, last, step] => (*first, *last, step.parse::<i64>().ok()?),
+        _ => return None,
+    };
+    // the sign of the step does not matter, the direction comes from the bounds
+    let step = step.unsigned_abs().max(1) as usize;
+    if let (Ok(from), Ok(to)) = (first.parse::<i64>(), last.parse::<i64>()) {
+        // a leading zero pads every number to the same width
+        let padded = |bound: &str| {
+            let digits = bound.trim_start_matches(['-', '+']);
+            digits.len() > 1 && digits.starts_with('0')
+        };
+        let width = if padded(first) || padded(last) {
+            first.len().max(last.len())
+        } else {
+            0
+        };
+        let values: Vec<i64> = if from <= to {
+            (from..=to).step_by(step).collect()
+        } else {
+            (to..=from).rev().step_by(step).collect()
+        };
+        return Some(
+            values
+                .into_iter()
+                .map(|n| {
+                    if n < 0 {
+                        format!("-{:0width$}", n.unsigned_abs(), width = width.saturating_sub(1))
+                    } else {
+                        format!("{:0width$}", n, width = width)
+                    }
+                })
+                .collect(),
+        );
+    }
+    let letter = |bound: &str| {
+        let mut chars = bound.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_alphabetic() => Some(c as u8),
+            _ => None,
+        }
+    };
+    let (from, to) = (letter(first)?, letter(last)?);
+    let values: Vec<u8> = if from <= to {
+        (from..=to).step_by(step).collect()
+    } else {
+        (to..=from).rev().step_by(step).collect()
+    };
+    Some(values.into_iter().map(|c| (c as char).to_string()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(text: &str) -> Vec<String> {
+        let word = Word {
+            parts: vec![WordPart::Lit(text.to_string())],
+        };
+        expand_braces(&word).iter().map(|word| word.literal().unwrap()).collect()
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(expand("a{b,c}d"), ["abd", "acd"]);
+        assert_eq!(expand("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+        assert_eq!(expand("x{a,{b,c}}"), ["xa", "xb", "xc"]);
+        assert_eq!(expand("{,a}"), ["", "a"]);
+        // a single item or an unclosed brace is no expansion
+        assert_eq!(expand("{a}"), ["{a}"]);
+        assert_eq!(expand("{a,b"), ["{a,b"]);
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(expand("{1..4}"), ["1", "2", "3", "4"]);
+        assert_eq!(expand("{3..1}"), ["3", "2", "1"]);
+        assert_eq!(expand("{-1..1}"), ["-1", "0", "1"]);
+        assert_eq!(expand("{08..10}"), ["08", "09", "10"]);
+        assert_eq!(expand("{c..a}"), ["c", "b", "a"]);
+        assert_eq!(expand("{1..a}"), ["{1..a}"]);
+    }
+
+    #[test]
+    fn steps() {
+        assert_eq!(expand("{1..10..3}"), ["1", "4", "7", "10"]);
+        assert_eq!(expand("{10..1..4}"), ["10", "6", "2"]);
+        assert_eq!(expand("{a..e..2}"), ["a", "c", "e"]);
+        // the sign of the step is ignored and 0 steps by 1
+        assert_eq!(expand("{1..5..-2}"), ["1", "3", "5"]);
+        assert_eq!(expand("{5..1..-2}"), ["5", "3", "1"]);
+        assert_eq!(expand("{1..3..0}"), ["1", "2", "3"]);
+        assert_eq!(expand("{1..3..x}"), ["{1..3..x}"]);
+    }
+}
@@ -0,0 +1,294 @@
+use super::auth::read_token_file;
+use super::connection::Connection;
+use super::protocol::{client_handshake, write_message, FrameReader, Message};
+use super::tls::{client_config, Identity};
+use super::utils::load_history;
+use super::MyShell;
+use nix::errno::Errno;
+use nix::libc::{c_int, ioctl, STDIN_FILENO, TIOCGWINSZ};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::pty::Winsize;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+use nix::unistd::read;
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub struct ClientConfig {
+    // host:port, [ipv6]:port or unix:/path
+    pub address: String,
+    // run the session on a pseudo-terminal
+    pub tty: bool,
+    pub token_file: String,
+    // CA the server certificate must be signed by, enables TLS
+    pub tls_ca: Option<String>,
+    // certificate for servers that authenticate clients by certificate
+    pub tls_identity: Option<Identity>,
+}
+
+impl ClientConfig {
+    pub fn new(address: String, token_file: String) -> ClientConfig {
+        ClientConfig {
+            address,
+            tty: false,
+            token_file,
+            tls_ca: None,
+            tls_identity: None,
+        }
+    }
+}
+
+static WINDOW_RESIZED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_window_resize(_: c_int) {
+    WINDOW_RESIZED.store(true, Ordering::SeqCst);
+}
+
+impl MyShell {
+    pub fn start_client(&mut self, config: ClientConfig) -> i32 {
+        let tls = match &config.tls_ca {
+            Some(ca) => match client_config(ca, config.tls_identity.as_ref()) {
+                Ok(c) => Some(c),
+                Err(err) => {
+                    eprintln!("myshell: {}", err);
+                    return 1;
+                }
+            },
+            None => None,
+        };
+        let mut stream = match Connection::connect(&config.address, tls) {
+            Ok(s) => s,
+            Err(err) => {
+                eprintln!("myshell: {}", err);
+                return 1;
+            }
+        };
+        // a Unix socket server trusts the permissions of its socket file instead,
+        // a server checking client certificates does not need the token either
+        let token = if stream.is_unix() {
+            Vec::new()
+        } else {
+            match read_token_file(&config.token_file) {
+                Ok(t) => t,
+                Err(_) if config.tls_identity.is_some() => Vec::new(),
+                Err(err) => {
+                    eprintln!("myshell: {}", err);
+                    return 1;
+                }
+            }
+        };
+        let mut from_server = FrameReader::new();
+        if let Err(err) = client_handshake(&mut stream, &mut from_server, &token) {
+            eprintln!("myshell: {}", err);
+            return 1;
+        }
+        let tty_size = if config.tty {
+            match terminal_size(STDIN_FILENO) {
+                Some(size) => Some(size),
+                None => {
+                    eprintln!("myshell: --tty requires a terminal");
+                    return 1;
+                }
+            }
+        } else {
+            None
+        };
+        if let Err(err) = write_message(&mut stream, &Message::StartSession(tty_size)) {
+            eprintln!("myshell: {}", err);
+            return 1;
+        }
+        if config.tty {
+            return self.start_tty_client(stream, from_server);
+        }
+
+        let mut rl = Editor::<()>::new();
+        let history_filename = load_history(&mut rl);
+
+        let mut input_closed = false;
+        loop {
+            let message = match from_server.read_message(&mut stream) {
+                Ok(Some(m)) => m,
+                Ok(None) => {
+                    eprintln!("myshell: connection closed by server");
+                    self.last_exit_code = 1;
+                    break;
+                }
+                Err(err) => {
+                    eprintln!("myshell: {}", err);
+                    self.last_exit_code = 1;
+                    break;
+                }
+            };
+            match message {
+                Message::Stdout(data) => {
+                    let mut stdout = io::stdout();
+                    stdout.write_all(&data).and_then(|_| stdout.flush()).unwrap_or(());
+                }
+                Message::Stderr(data) => {
+                    io::stderr().write_all(&data).unwrap_or(());
+                }
+                Message::ExitStatus(status) => self.last_exit_code = status,
+                Message::Shutdown(grace) => eprintln!("myshell: {}", shutdown_notice(grace)),
+                Message::Prompt(prompt) => {
+                    if input_closed {
+                        continue;
+                    }
+                    let reply = match rl.readline(&prompt) {
+                        Ok(line) => {
+                            rl.add_history_entry(line.as_str());
+                            Message::Input((line + "\n").into_bytes())
+                        }
+                        // let the remote session finish and report its status
+                        Err(ReadlineError::Interrupted | ReadlineError::Eof) => {
+                            input_closed = true;
+                            Message::Close(0)
+                        }
+                        Err(err) => {
+                            eprintln!("myshell: Error: {:?}", err);
+                            input_closed = true;
+                            Message::Close(1)
+                        }
+                    };
+                    if let Err(err) = write_message(&mut stream, &reply) {
+                        eprintln!("myshell: {}", err);
+                        self.last_exit_code = 1;
+                        break;
+                    }
+                }
+                Message::Close(status) => {
+                    self.last_exit_code = status;
+                    break;
+                }
+                Message::Error(err) => {
+                    eprintln!("myshell: {}", err);
+                    self.last_exit_code = 1;
+                    break;
+                }
+                _ => {
+                    eprintln!("myshell: protocol error: unexpected message from server");
+                    self.last_exit_code = 1;
+                    break;
+                }
+            }
+        }
+        rl.save_history(&history_filename).unwrap_or_else(|err| {
+            println!("Warning: could not save history file: {}", err);
+        });
+        self.last_exit_code
+    }
+
+    // the remote session runs on a pty: the local terminal goes raw and every
+    // keystroke is sent as is, line editing and echo happen on the server
+    fn start_tty_client(&mut self, mut stream: Connection, mut from_server: FrameReader) -> i32 {
+        let saved = match tcgetattr(STDIN_FILENO) {
+            Ok(t) => t,
+            Err(err) => {
+                eprintln!("myshell: {}", err);
+                return 1;
+            }
+        };
+        let mut raw = saved.clone();
+        cfmakeraw(&mut raw);
+        if let Err(err) = tcsetattr(STDIN_FILENO, SetArg::TCSANOW, &raw) {
+            eprintln!("myshell: {}", err);
+            return 1;
+        }
+        let on_resize = SigAction::new(
+            SigHandler::Handler(on_window_resize),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        unsafe { sigaction(Signal::SIGWINCH, &on_resize) }.ok();
+
+        let result = self.relay_terminal(&mut stream, &mut from_server);
+        tcsetattr(STDIN_FILENO, SetArg::TCSANOW, &saved).unwrap_or(());
+        match result {
+            Ok(status) => status,
+            Err(err) => {
+                eprintln!("myshell: {}", err);
+                1
+            }
+        }
+    }
+
+    // returns the final status of the remote session
+    fn relay_terminal(&mut self, stream: &mut Connection, from_server: &mut FrameReader) -> Result<i32, String> {
+        let mut stdin_open = true;
+        let mut chunk = [0u8; 4096];
+        loop {
+            if WINDOW_RESIZED.swap(false, Ordering::SeqCst) {
+                if let Some((rows, cols)) = terminal_size(STDIN_FILENO) {
+                    write_message(stream, &Message::WindowSize(rows, cols))?;
+                }
+            }
+            let mut poll_fds = vec![PollFd::new(stream.as_raw_fd(), PollFlags::POLLIN)];
+            if stdin_open {
+                poll_fds.push(PollFd::new(STDIN_FILENO, PollFlags::POLLIN));
+            }
+            let timeout = if stream.has_buffered_input() { 0 } else { -1 };
+            match poll(&mut poll_fds, timeout) {
+                Ok(_) => {}
+                Err(Errno::EINTR) => continue,
+                Err(err) => return Err(err.to_string()),
+            }
+            let ready = |i: usize| poll_fds.get(i).and_then(|pfd| pfd.revents()).is_some_and(|r| !r.is_empty());
+
+            if ready(1) {
+                let n = match read(STDIN_FILENO, &mut chunk) {
+                    Ok(n) => n,
+                    Err(Errno::EINTR) => continue,
+                    Err(err) => return Err(err.to_string()),
+                };
+                let message = if n == 0 {
+                    stdin_open = false;
+                    Message::Close(0)
+                } else {
+                    Message::Input(chunk[..n].to_vec())
+                };
+                write_message(stream, &message)?;
+            }
+            if ready(0) || stream.has_buffered_input() {
+                match stream.read_available().map_err(|err| err.to_string())? {
+                    Some(data) => from_server.push(&data),
+                    None => return Err("connection closed by server".to_string()),
+                }
+                while let Some(message) = from_server.next_message()? {
+                    match message {
+                        Message::Stdout(data) => {
+                            let mut stdout = io::stdout();
+                            stdout.write_all(&data).and_then(|_| stdout.flush()).unwrap_or(());
+                        }
+                        Message::Stderr(data) => io::stderr().write_all(&data).unwrap_or(()),
+                        Message::ExitStatus(status) => self.last_exit_code = status,
+                        // a raw terminal needs the carriage returns
+                        Message::Shutdown(grace) => eprint!("\r\nmyshell: {}\r\n", shutdown_notice(grace)),
+                        Message::Close(status) => return Ok(status),
+                        Message::Error(err) => return Err(err),
+                        _ => return Err("protocol error: unexpected message from server".to_string()),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn shutdown_notice(grace: u32) -> String {
+    format!("server is shutting down, the session will be closed in {}s", grace)
+}
+
+// (rows, columns) of the terminal behind `fd`
+fn terminal_size(fd: i32) -> Option<(u16, u16)> {
+    let mut size = Winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    if unsafe { ioctl(fd, TIOCGWINSZ, &mut size) } == -1 {
+        return None;
+    }
+    Some((size.ws_row, size.ws_col))
+}
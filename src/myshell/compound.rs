@@ -0,0 +1,255 @@
+use super::lexer::Word;
+use super::parser::{CaseClause, Command, CompoundCommand, ForClause, IfClause, List, LoopClause};
+use super::descriptors::{restore, Descriptors};
+use super::pattern::Pattern;
+use super::utils::wait_for;
+use super::{Jump, MyShell};
+
+use nix::unistd::{fork, ForkResult, Pid};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::{mem, process};
+
+impl MyShell {
+    pub fn run_compound(&mut self, compound: &CompoundCommand) -> i32 {
+        match compound {
+            CompoundCommand::If(clause) => self.run_if(clause),
+            CompoundCommand::Loop(clause) => self.run_loop(clause),
+            CompoundCommand::For(clause) => self.run_for(clause),
+            CompoundCommand::Case(clause) => self.run_case(clause),
+            CompoundCommand::Arith(expr) => self.run_arith(expr),
+            CompoundCommand::Subshell(list) => self.run_subshell(list),
+            CompoundCommand::Group(list) => self.run_list(list),
+        }
+    }
+
+    // variables, functions and the directory changed by the list stay in the copy
+    fn run_subshell(&mut self, list: &List) -> i32 {
+        io::stdout().flush().unwrap_or(());
+        match unsafe { fork() } {
+            Ok(ForkResult::Child) => {
+                let status = self.run_list(list);
+                io::stdout().flush().unwrap_or(());
+                process::exit(status);
+            }
+            Ok(ForkResult::Parent { child }) => wait_for(child).unwrap_or_else(|err| {
+                eprintln!("myshell: {}", err);
+                1
+            }),
+            Err(err) => {
+                eprintln!("myshell: fork() failed: {}", err);
+                1
+            }
+        }
+    }
+
+    // the status is 0 when the expression is not zero
+    fn run_arith(&mut self, expr: &Word) -> i32 {
+        match self.expand_arith(&expr.parts) {
+            Ok(value) => (value == 0) as i32,
+            Err(err) => {
+                eprintln!("myshell: {}", err);
+                1
+            }
+        }
+    }
+
+    fn run_if(&mut self, clause: &IfClause) -> i32 {
+        for (condition, body) in &clause.branches {
+            let status = self.run_list(condition);
+            if self.jump.is_some() || self.time_to_exit {
+                return status;
+            }
+            if status == 0 {
+                return self.run_list(body);
+            }
+        }
+        match &clause.else_body {
+            Some(body) => self.run_list(body),
+            None => 0,
+        }
+    }
+
+    fn run_loop(&mut self, clause: &LoopClause) -> i32 {
+        let mut status = 0;
+        self.loop_depth += 1;
+        loop {
+            let condition = self.run_list(&clause.condition);
+            if self.leave_loop() || (condition == 0) == clause.until {
+                break;
+            }
+            status = self.run_list(&clause.body);
+            if self.leave_loop() {
+                break;
+            }
+        }
+        self.loop_depth -= 1;
+        status
+    }
+
+    fn run_for(&mut self, clause: &ForClause) -> i32 {
+        let values = match &clause.words {
+            Some(words) => match self.expand_words(words).and_then(|words| self.glob_all(words)) {
+                Ok(values) => values,
+                Err(err) => {
+                    eprintln!("myshell: {}", err);
+                    return 1;
+                }
+            },
+            None => self.positional.clone(),
+        };
+        let mut status = 0;
+        self.loop_depth += 1;
+        for value in values {
+            self.local_vars.insert(clause.name.clone(), value);
+            status = self.run_list(&clause.body);
+            if self.leave_loop() {
+                break;
+            }
+        }
+        self.loop_depth -= 1;
+        status
+    }
+
+    fn run_case(&mut self, clause: &CaseClause) -> i32 {
+        let word = match self.expand_word(&clause.word, false) {
+            Ok(fields) => fields.concat(),
+            Err(err) => {
+                eprintln!("myshell: {}", err);
+                return 1;
+            }
+        };
+        for item in &clause.items {
+            for pattern in &item.patterns {
+                let pattern = match self.expand_pattern(pattern) {
+                    Ok(pattern) => pattern,
+                    Err(err) => {
+                        eprintln!("myshell: {}", err);
+                        return 1;
+                    }
+                };
+                if Pattern::new(&pattern, self.glob_options.extglob).matches(&word) {
+                    return self.run_list(&item.body);
+                }
+            }
+        }
+        0
+    }
+
+    // after a loop body, true when the loop has to stop
+    fn leave_loop(&mut self) -> bool {
+        match self.jump {
+            Some(Jump::Break(n)) => {
+                self.jump = if n > 1 { Some(Jump::Break(n - 1)) } else { None };
+                true
+            }
+            // continues a loop further out
+            Some(Jump::Continue(n)) if n > 1 => {
+                self.jump = Some(Jump::Continue(n - 1));
+                true
+            }
+            Some(Jump::Continue(_)) => {
+                self.jump = None;
+                false
+            }
+            // return passes through loops up to the function call
+            Some(Jump::Return(_)) => true,
+            None => self.time_to_exit,
+        }
+    }
+
+    // A compound command runs in the shell itself, so its redirections replace
+    // the shell's own descriptors for the time it runs
+    pub fn with_descriptors(&mut self, mut descs: Descriptors, run: impl FnOnce(&mut MyShell) -> i32) -> i32 {
+        if descs.is_empty() {
+            return run(self);
+        }
+        io::stdout().flush().unwrap_or(());
+        let saved = descs.swap_in();
+        let status = run(self);
+        io::stdout().flush().unwrap_or(());
+        restore(saved);
+        status
+    }
+
+    // a step of a pipeline that runs in a copy of the shell
+    pub fn fork_step(
+        &mut self,
+        descriptors: &mut [Descriptors],
+        step: usize,
+        run: impl FnOnce(&mut MyShell) -> i32,
+    ) -> Result<Pid, String> {
+        io::stdout().flush().map_err(|err| err.to_string())?;
+        match unsafe { fork() } {
+            Ok(ForkResult::Child) => {
+                // descriptors of the other steps would keep their pipes open
+                for (i, descs) in descriptors.iter_mut().enumerate() {
+                    if i != step {
+                        descs.close_all();
+                    }
+                }
+                descriptors[step].install();
+                let status = run(self);
+                process::exit(status);
+            }
+            Ok(ForkResult::Parent { child }) => {
+                descriptors[step].close_all();
+                Ok(child)
+            }
+            Err(err) => {
+                descriptors[step].close_all();
+                Err(format!("fork() failed: {}", err))
+            }
+        }
+    }
+
+    pub fn run_command_node(&mut self, command: &Command) -> i32 {
+        match command {
+            Command::Compound(compound, _) => self.run_compound(compound),
+            Command::Function(function) => {
+                self.functions.insert(function.name.clone(), function.clone());
+                0
+            }
+            // simple commands are expanded and run by `execute_pipeline`
+            Command::Simple(_) => 0,
+        }
+    }
+
+    // `command` is the expanded name and arguments
+    pub fn call_function(&mut self, command: &[String]) -> i32 {
+        let function = match self.functions.get(&command[0]) {
+            Some(function) => function.clone(),
+            None => return 127,
+        };
+        let positional = mem::replace(&mut self.positional, command[1..].to_vec());
+        // loops of the caller can't be left from inside the function
+        let loop_depth = mem::replace(&mut self.loop_depth, 0);
+        self.local_frames.push(HashMap::new());
+
+        let mut status = if function.redirects.is_empty() {
+            self.run_list(&function.body)
+        } else {
+            match self.redirect_descriptors(&function.redirects) {
+                Ok(descs) => self.with_descriptors(descs, |shell| shell.run_list(&function.body)),
+                Err(err) => {
+                    eprintln!("myshell: {}", err);
+                    1
+                }
+            }
+        };
+        if let Some(Jump::Return(code)) = self.jump {
+            self.jump = None;
+            status = code;
+        }
+
+        for (name, value) in self.local_frames.pop().unwrap_or_default() {
+            match value {
+                Some(value) => self.local_vars.insert(name, value),
+                None => self.local_vars.remove(&name),
+            };
+        }
+        self.loop_depth = loop_depth;
+        self.positional = positional;
+        status
+    }
+}
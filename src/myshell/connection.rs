@@ -0,0 +1,336 @@
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use nix::sys::stat::{umask, Mode};
+use rustls::{ClientConfig, ClientConnection, ConnectionCommon, ServerConfig, ServerConnection, StreamOwned};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::tls::server_name;
+
+pub const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1";
+pub const DEFAULT_SOCKET_MODE: u32 = 0o600;
+const UNIX_PREFIX: &str = "unix:";
+
+// a client connection of the remote shell, over TCP or a Unix domain socket,
+// TCP optionally wrapped in TLS
+pub enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    TlsServer(Box<StreamOwned<ServerConnection, TcpStream>>),
+    TlsClient(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+#[derive(Clone, Debug)]
+pub enum Peer {
+    Remote(SocketAddr),
+    // credentials the kernel reports for the other end of a Unix socket
+    Local { uid: u32, pid: i32 },
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Peer::Remote(addr) => write!(f, "{}", addr),
+            Peer::Local { uid, pid } => write!(f, "uid {} (pid {})", uid, pid),
+        }
+    }
+}
+
+impl Peer {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Remote(addr) => Some(addr.ip()),
+            Peer::Local { .. } => None,
+        }
+    }
+}
+
+impl Connection {
+    // `address` is host:port, [v6 address]:port, unix:/path or a path to a socket
+    pub fn connect(address: &str, tls: Option<Arc<ClientConfig>>) -> Result<Connection, String> {
+        let connection = match unix_socket_path(address) {
+            Some(_) if tls.is_some() => return Err("TLS is not supported on Unix sockets".to_string()),
+            Some(path) => UnixStream::connect(path).map(Connection::Unix),
+            None => TcpStream::connect(address).map(Connection::Tcp),
+        };
+        let connection = connection.map_err(|err| format!("could not connect to {}: {}", address, err))?;
+        match (connection, tls) {
+            // the handshake happens on the first read or write
+            (Connection::Tcp(sock), Some(config)) => {
+                let conn = ClientConnection::new(config, server_name(address)?).map_err(|err| err.to_string())?;
+                Ok(Connection::TlsClient(Box::new(StreamOwned::new(conn, sock))))
+            }
+            (connection, _) => Ok(connection),
+        }
+    }
+
+    pub fn accept_tls(self, config: Arc<ServerConfig>) -> Result<Connection, String> {
+        match self {
+            Connection::Tcp(sock) => {
+                let conn = ServerConnection::new(config).map_err(|err| err.to_string())?;
+                Ok(Connection::TlsServer(Box::new(StreamOwned::new(conn, sock))))
+            }
+            _ => Err("TLS is only supported on TCP connections".to_string()),
+        }
+    }
+
+    pub fn is_unix(&self) -> bool {
+        matches!(self, Connection::Unix(_))
+    }
+
+    // the TLS handshake succeeded only if the certificate was verified
+    pub fn has_client_certificate(&self) -> bool {
+        match self {
+            Connection::TlsServer(stream) => stream.conn.peer_certificates().is_some(),
+            _ => false,
+        }
+    }
+
+    fn tcp_socket(&self) -> Option<&TcpStream> {
+        match self {
+            Connection::Tcp(stream) => Some(stream),
+            Connection::Unix(_) => None,
+            Connection::TlsServer(stream) => Some(&stream.sock),
+            Connection::TlsClient(stream) => Some(&stream.sock),
+        }
+    }
+
+    pub fn peer(&self) -> Result<Peer, String> {
+        match self {
+            Connection::Unix(stream) => {
+                let cred = getsockopt(stream.as_raw_fd(), PeerCredentials).map_err(|err| err.to_string())?;
+                Ok(Peer::Local {
+                    uid: cred.uid(),
+                    pid: cred.pid(),
+                })
+            }
+            _ => self
+                .tcp_socket()
+                .map(|sock| sock.peer_addr())
+                .unwrap_or_else(|| Err(io::ErrorKind::Unsupported.into()))
+                .map(Peer::Remote)
+                .map_err(|err| err.to_string()),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Unix(stream) => stream.set_read_timeout(timeout),
+            _ => self.tcp_socket().map_or(Ok(()), |sock| sock.set_read_timeout(timeout)),
+        }
+    }
+
+    // reads what arrived once poll reported the connection readable, None at the end of the stream
+    pub fn read_available(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self {
+            Connection::TlsServer(stream) => tls_read_available(&mut stream.conn, &mut stream.sock),
+            Connection::TlsClient(stream) => tls_read_available(&mut stream.conn, &mut stream.sock),
+            _ => {
+                let mut chunk = [0u8; 4096];
+                match self.read(&mut chunk)? {
+                    0 => Ok(None),
+                    n => Ok(Some(chunk[..n].to_vec())),
+                }
+            }
+        }
+    }
+
+    // TLS may hold decrypted data the socket will not report to poll again
+    pub fn has_buffered_input(&mut self) -> bool {
+        match self {
+            Connection::TlsServer(stream) => tls_has_buffered_input(&mut stream.conn),
+            Connection::TlsClient(stream) => tls_has_buffered_input(&mut stream.conn),
+            _ => false,
+        }
+    }
+
+    pub fn shutdown(&mut self) {
+        match self {
+            Connection::Tcp(stream) => stream.shutdown(Shutdown::Both).unwrap_or(()),
+            Connection::Unix(stream) => stream.shutdown(Shutdown::Both).unwrap_or(()),
+            Connection::TlsServer(stream) => tls_shutdown(&mut stream.conn, &mut stream.sock),
+            Connection::TlsClient(stream) => tls_shutdown(&mut stream.conn, &mut stream.sock),
+        }
+    }
+}
+
+fn tls_read_available<D>(conn: &mut ConnectionCommon<D>, sock: &mut TcpStream) -> io::Result<Option<Vec<u8>>> {
+    let state = conn.process_new_packets().map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    if state.plaintext_bytes_to_read() == 0 && !state.peer_has_closed() {
+        // poll reported the socket readable, so this does not block
+        if conn.read_tls(sock)? == 0 {
+            return Ok(None);
+        }
+        conn.process_new_packets().map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        // handshake messages and key updates need an answer
+        while conn.wants_write() {
+            conn.write_tls(sock)?;
+        }
+    }
+    // an incomplete record leaves nothing to read yet
+    let mut data = Vec::new();
+    match conn.reader().read_to_end(&mut data) {
+        Ok(_) if data.is_empty() => Ok(None),
+        Ok(_) => Ok(Some(data)),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(Some(data)),
+        Err(err) => Err(err),
+    }
+}
+
+fn tls_has_buffered_input<D>(conn: &mut ConnectionCommon<D>) -> bool {
+    // an error has to surface from the next read
+    conn.process_new_packets()
+        .map_or(true, |state| state.plaintext_bytes_to_read() > 0 || state.peer_has_closed())
+}
+
+fn tls_shutdown<D>(conn: &mut ConnectionCommon<D>, sock: &mut TcpStream) {
+    conn.send_close_notify();
+    while conn.wants_write() {
+        if conn.write_tls(sock).is_err() {
+            break;
+        }
+    }
+    sock.shutdown(Shutdown::Both).unwrap_or(());
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            Connection::Unix(stream) => stream.read(buf),
+            Connection::TlsServer(stream) => stream.read(buf),
+            Connection::TlsClient(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            Connection::Unix(stream) => stream.write(buf),
+            Connection::TlsServer(stream) => stream.write(buf),
+            Connection::TlsClient(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            Connection::Unix(stream) => stream.flush(),
+            Connection::TlsServer(stream) => stream.flush(),
+            Connection::TlsClient(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Connection {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Connection::Tcp(stream) => stream.as_raw_fd(),
+            Connection::Unix(stream) => stream.as_raw_fd(),
+            Connection::TlsServer(stream) => stream.sock.as_raw_fd(),
+            Connection::TlsClient(stream) => stream.sock.as_raw_fd(),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, String),
+}
+
+impl Listener {
+    pub fn bind_tcp(bind: &str, port: &str) -> Result<Listener, String> {
+        let ip: IpAddr = bind
+            .parse()
+            .map_err(|_| format!("invalid bind address '{}': expected an IPv4 or IPv6 address", bind))?;
+        let port = parse_port(port)?;
+        TcpListener::bind(SocketAddr::new(ip, port))
+            .map(Listener::Tcp)
+            .map_err(|err| format!("could not listen on {}: {}", SocketAddr::new(ip, port), err))
+    }
+
+    // only users with write permission on the socket file can connect
+    pub fn bind_unix(path: &str, mode: u32) -> Result<Listener, String> {
+        remove_stale_socket(path)?;
+        // no window in which the socket exists with looser permissions
+        let old_mask = umask(Mode::from_bits_truncate(0o777));
+        let listener = UnixListener::bind(path);
+        umask(old_mask);
+        let listener = listener.map_err(|err| format!("could not listen on {}: {}", path, err))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(|err| format!("{}: {}", path, err))?;
+        Ok(Listener::Unix(listener, path.to_string()))
+    }
+
+    pub fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Connection::Tcp(stream)),
+            Listener::Unix(listener, _) => listener.accept().map(|(stream, _)| Connection::Unix(stream)),
+        }
+    }
+
+    pub fn set_nonblocking(&self) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(true),
+            Listener::Unix(listener, _) => listener.set_nonblocking(true),
+        }
+    }
+
+    pub fn address(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener
+                .local_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_default(),
+            Listener::Unix(_, path) => String::from(UNIX_PREFIX) + path,
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener, _) => listener.as_raw_fd(),
+        }
+    }
+}
+
+pub fn parse_port(port: &str) -> Result<u16, String> {
+    match port.parse::<u16>() {
+        Ok(p) if p != 0 => Ok(p),
+        _ => Err(format!("invalid port '{}': expected a number between 1 and 65535", port)),
+    }
+}
+
+fn unix_socket_path(address: &str) -> Option<&str> {
+    if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+        return Some(path);
+    }
+    if address.starts_with('/') || address.starts_with('.') {
+        return Some(address);
+    }
+    None
+}
+
+// a socket file left by a server that is gone would make bind fail
+fn remove_stale_socket(path: &str) -> Result<(), String> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(_) => return Ok(()),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(format!("{}: file exists and is not a socket", path));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(format!("{}: another server is listening on it", path));
+    }
+    fs::remove_file(path).map_err(|err| format!("{}: {}", path, err))
+}
@@ -0,0 +1,210 @@
+use nix::fcntl::{fcntl, open, FcntlArg, FdFlag, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{close, dup2};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+
+// descriptors 0 to MAX_FD can be redirected
+pub const MAX_FD: i32 = 9;
+// the shell keeps what it opens at this number and above, out of their way
+const PRIVATE_FD: i32 = 10;
+pub const CLOSED: i32 = -1;
+
+// What a command gets at each descriptor number: a descriptor the shell opened
+// for it, or CLOSED. The numbers that are not set are the shell's own.
+#[derive(Debug, Default)]
+pub struct Descriptors {
+    slots: BTreeMap<i32, i32>,
+}
+
+impl Descriptors {
+    pub fn new() -> Descriptors {
+        Descriptors::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    // `fd` takes ownership of `desc`, closing what it held before
+    pub fn set(&mut self, fd: i32, desc: i32) -> Result<(), String> {
+        let desc = if desc != CLOSED { private_fd(desc)? } else { desc };
+        if let Some(old) = self.slots.insert(fd, desc) {
+            if old != CLOSED {
+                let _ = close(old);
+            }
+        }
+        Ok(())
+    }
+
+    // a new descriptor for what `fd` refers to, for n>&fd
+    pub fn duplicate(&self, fd: i32) -> Result<i32, String> {
+        let bad = || format!("{}: bad file descriptor", fd);
+        let desc = match self.slots.get(&fd) {
+            Some(&CLOSED) => return Err(bad()),
+            Some(&desc) => desc,
+            // descriptors the shell uses itself are close-on-exec, only the standard
+            // ones and those inherited from the parent can be duplicated
+            None if fd > 2 => match fcntl(fd, FcntlArg::F_GETFD) {
+                Ok(flags) if !FdFlag::from_bits_truncate(flags).contains(FdFlag::FD_CLOEXEC) => fd,
+                _ => return Err(bad()),
+            },
+            None => fd,
+        };
+        fcntl(desc, FcntlArg::F_DUPFD_CLOEXEC(PRIVATE_FD)).map_err(|_| bad())
+    }
+
+    pub fn close_all(&mut self) {
+        for (_, desc) in mem::take(&mut self.slots) {
+            if desc != CLOSED {
+                let _ = close(desc);
+            }
+        }
+    }
+
+    // stdin, stdout and stderr for an internal command, which owns the ones
+    // that are not the shell's. A closed one is replaced by /dev/null.
+    pub fn take_standard(&mut self) -> [i32; 3] {
+        let mut descs = [0, 1, 2];
+        for (fd, desc) in descs.iter_mut().enumerate() {
+            match self.slots.remove(&(fd as i32)) {
+                Some(CLOSED) => {
+                    *desc = open("/dev/null", OFlag::O_RDWR | OFlag::O_CLOEXEC, Mode::empty()).unwrap_or(fd as i32)
+                }
+                Some(owned) => *desc = owned,
+                None => {}
+            }
+        }
+        descs
+    }
+
+    // Hands the descriptors to a program about to be started. The standard ones
+    // go through `Stdio`, the others are put in place right before exec; the
+    // caller closes them once the program runs.
+    pub fn attach(&mut self, command: &mut Command) {
+        let mut rest = BTreeMap::new();
+        for (fd, desc) in mem::take(&mut self.slots) {
+            let stdio = || unsafe { Stdio::from_raw_fd(desc) };
+            match fd {
+                0 if desc != CLOSED => command.stdin(stdio()),
+                1 if desc != CLOSED => command.stdout(stdio()),
+                2 if desc != CLOSED => command.stderr(stdio()),
+                _ => {
+                    rest.insert(fd, desc);
+                    continue;
+                }
+            };
+        }
+        let installed: Vec<(i32, i32)> = rest.iter().map(|(&fd, &desc)| (fd, desc)).collect();
+        unsafe {
+            command.pre_exec(move || {
+                for &(fd, desc) in &installed {
+                    if desc == CLOSED {
+                        let _ = close(fd);
+                    } else {
+                        dup2(desc, fd)?;
+                    }
+                }
+                Ok(())
+            });
+        }
+        self.slots = rest;
+    }
+
+    // puts the descriptors in place for good, in a forked copy of the shell
+    pub fn install(&mut self) {
+        for (fd, desc) in mem::take(&mut self.slots) {
+            if desc == CLOSED {
+                let _ = close(fd);
+            } else {
+                let _ = dup2(desc, fd);
+                let _ = close(desc);
+            }
+        }
+    }
+
+    // Puts the descriptors in place for a command that runs in the shell itself
+    // and returns what to put back with `restore`.
+    pub fn swap_in(&mut self) -> Vec<(i32, Option<(i32, bool)>)> {
+        let mut saved = Vec::new();
+        for (fd, desc) in mem::take(&mut self.slots) {
+            // None when the shell did not have the descriptor open
+            let previous = fcntl(fd, FcntlArg::F_GETFD).ok().and_then(|flags| {
+                let cloexec = FdFlag::from_bits_truncate(flags).contains(FdFlag::FD_CLOEXEC);
+                let copy = fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(PRIVATE_FD)).ok()?;
+                Some((copy, cloexec))
+            });
+            saved.push((fd, previous));
+            if desc == CLOSED {
+                let _ = close(fd);
+            } else {
+                let _ = dup2(desc, fd);
+                let _ = close(desc);
+            }
+        }
+        saved
+    }
+}
+
+pub fn restore(saved: Vec<(i32, Option<(i32, bool)>)>) {
+    for (fd, previous) in saved {
+        match previous {
+            Some((copy, cloexec)) => {
+                let _ = dup2(copy, fd);
+                let _ = close(copy);
+                if cloexec {
+                    let _ = fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC));
+                }
+            }
+            None => {
+                let _ = close(fd);
+            }
+        }
+    }
+}
+
+// moves `desc` to a close-on-exec number the user can't redirect
+fn private_fd(desc: i32) -> Result<i32, String> {
+    if desc >= PRIVATE_FD {
+        return Ok(desc);
+    }
+    let moved = fcntl(desc, FcntlArg::F_DUPFD_CLOEXEC(PRIVATE_FD));
+    let _ = close(desc);
+    moved.map_err(|err| err.to_string())
+}
+
+// moves `desc` out of the way like `private_fd`, but programs started later
+// inherit it
+pub fn inherited_fd(desc: i32) -> Result<i32, String> {
+    let moved = fcntl(desc, FcntlArg::F_DUPFD(PRIVATE_FD));
+    let _ = close(desc);
+    moved.map_err(|err| err.to_string())
+}
+
+// closes everything from PRIVATE_FD up, in a copy of the shell that runs
+// next to the commands of the shell and must not keep their pipes open
+pub fn close_private() {
+    let descs: Vec<i32> = match fs::read_dir("/proc/self/fd") {
+        Ok(entries) => entries
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+            .filter(|&desc| desc >= PRIVATE_FD)
+            .collect(),
+        Err(_) => return,
+    };
+    for desc in descs {
+        let _ = close(desc);
+    }
+}
+
+// files the shell keeps open for itself, like the script it reads
+pub fn private_file(file: File) -> File {
+    match fcntl(file.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(PRIVATE_FD)) {
+        Ok(desc) => unsafe { File::from_raw_fd(desc) },
+        Err(_) => file,
+    }
+}
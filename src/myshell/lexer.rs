@@ -0,0 +1,1080 @@
+use std::fmt;
+
+// Splits a command line into words and operators. Words keep track of how
+// each piece was quoted, expansions happen later on the parsed commands.
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseError {
+    // the input ended inside a quote or a compound command, more lines may complete it
+    Incomplete(String),
+    // the input ended in the body of a here-document, only its delimiter can end it
+    HereDoc { delimiter: String, strip: bool },
+    Syntax(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Incomplete(message) | ParseError::Syntax(message) => write!(f, "{}", message),
+            ParseError::HereDoc { delimiter, .. } => {
+                write!(f, "here-document delimited by end-of-file (wanted `{}')", delimiter)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum WordPart {
+    Lit(String),
+    // single quoted or escaped with a backslash, taken as is
+    Quoted(String),
+    // inside double quotes only Lit, Var and CmdSubst appear
+    DQuoted(Vec<WordPart>),
+    // a variable name, or a positional or special parameter like 1 or @
+    Var(String),
+    // source between $( and ) or backquotes, still unparsed
+    CmdSubst(String),
+    // ${name...} with an operator, plain ${name} is a Var
+    Param(Box<ParamExp>),
+    // $((...)), the expression after its own expansions
+    Arith(Vec<WordPart>),
+    // <(...) or, when `output`, >(...)
+    ProcSubst { output: bool, source: String },
+    // the elements of name=(...), after the Lit with the name
+    Array(Vec<Word>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParamOp {
+    // ${name[subscript]}
+    Value,
+    // ${#name}
+    Length,
+    // ${!name[@]}, the indexes or keys of an array
+    Keys,
+    // ${name:-word}, `colon` when an empty value counts as unset
+    Default { colon: bool, word: Word },
+    // ${name:=word}
+    Assign { colon: bool, word: Word },
+    // ${name:?word}
+    Error { colon: bool, word: Word },
+    // ${name:+word}
+    Alternate { colon: bool, word: Word },
+    // ${name#pattern} and ${name##pattern}
+    TrimPrefix { longest: bool, pattern: Word },
+    // ${name%pattern} and ${name%%pattern}
+    TrimSuffix { longest: bool, pattern: Word },
+    // ${name/pattern/string} and ${name//pattern/string}, /# and /% anchor
+    // the pattern at the start or the end
+    Replace { all: bool, anchor: Option<char>, pattern: Word, replacement: Word },
+    // ${name:offset:length}
+    Substring { offset: Word, length: Option<Word> },
+    // ${name^}, ${name^^}, ${name,} and ${name,,}
+    Upper { all: bool },
+    Lower { all: bool },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParamExp {
+    pub name: String,
+    // ${name[subscript]...}, @ and * for all elements
+    pub subscript: Option<Word>,
+    pub op: ParamOp,
+    // the text between the braces, for messages
+    pub source: String,
+}
+
+impl ParamExp {
+    // name or name[subscript], for messages
+    pub fn target(&self) -> String {
+        match &self.subscript {
+            Some(subscript) => format!("{}[{}]", self.name, subscript.source()),
+            None => self.name.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Word {
+    pub parts: Vec<WordPart>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RedirOp {
+    // <
+    In,
+    // >
+    Out,
+    // >>
+    Append,
+    // <>
+    ReadWrite,
+    // &>, and >& when followed by a file name
+    OutErr,
+    // &>>
+    AppendErr,
+    // n>&m
+    DupOut,
+    // n<&m
+    DupIn,
+    // <<word, and <<-word that strips leading tabs; the body is the target
+    HereDoc(bool),
+    // <<<word
+    HereString,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    Word(Word),
+    // file descriptor written right before the operator, like the 2 in 2>
+    Redirect(Option<i32>, RedirOp),
+    Pipe,
+    Semi,
+    // ;; ends a case item
+    DSemi,
+    Amp,
+    AndIf,
+    OrIf,
+    LParen,
+    RParen,
+    Newline,
+    // ((...)) as a command
+    Arith(Vec<WordPart>),
+}
+
+impl Token {
+    // how the token is shown in syntax errors
+    pub fn describe(&self) -> String {
+        match self {
+            Token::Word(word) => word.source(),
+            Token::Redirect(fd, op) => {
+                let fd = fd.map(|fd| fd.to_string()).unwrap_or_default();
+                fd + match op {
+                    RedirOp::In => "<",
+                    RedirOp::Out => ">",
+                    RedirOp::Append => ">>",
+                    RedirOp::ReadWrite => "<>",
+                    RedirOp::OutErr => "&>",
+                    RedirOp::AppendErr => "&>>",
+                    RedirOp::DupOut => ">&",
+                    RedirOp::DupIn => "<&",
+                    RedirOp::HereDoc(false) => "<<",
+                    RedirOp::HereDoc(true) => "<<-",
+                    RedirOp::HereString => "<<<",
+                }
+            }
+            Token::Pipe => "|".to_string(),
+            Token::Semi => ";".to_string(),
+            Token::DSemi => ";;".to_string(),
+            Token::LParen => "(".to_string(),
+            Token::RParen => ")".to_string(),
+            Token::Amp => "&".to_string(),
+            Token::AndIf => "&&".to_string(),
+            Token::OrIf => "||".to_string(),
+            Token::Newline => "newline".to_string(),
+            Token::Arith(parts) => format!("(({}))", parts_source(parts)),
+        }
+    }
+}
+
+impl Word {
+    fn push_lit(&mut self, c: char) {
+        match self.parts.last_mut() {
+            Some(WordPart::Lit(s)) => s.push(c),
+            _ => self.parts.push(WordPart::Lit(c.to_string())),
+        }
+    }
+
+    fn push_quoted(&mut self, c: char) {
+        match self.parts.last_mut() {
+            Some(WordPart::Quoted(s)) => s.push(c),
+            _ => self.parts.push(WordPart::Quoted(c.to_string())),
+        }
+    }
+
+    // the word without quoting, when it has no expansions
+    pub fn literal(&self) -> Option<String> {
+        let mut result = String::new();
+        for part in &self.parts {
+            match part {
+                WordPart::Lit(s) | WordPart::Quoted(s) => result += s,
+                WordPart::DQuoted(inner) => result += &Word { parts: inner.clone() }.literal()?,
+                _ => return None,
+            }
+        }
+        Some(result)
+    }
+
+    // reserved words only count when written without quotes
+    pub fn is_reserved(&self, name: &str) -> bool {
+        matches!(self.parts.as_slice(), [WordPart::Lit(s)] if s == name)
+    }
+
+    // name=value, name+=value or name[subscript]=value
+    pub fn is_assignment(&self) -> bool {
+        let first = match self.parts.first() {
+            Some(WordPart::Lit(first)) => first,
+            _ => return false,
+        };
+        if let Some((name, _)) = first.split_once('[') {
+            if is_name(name) {
+                return self
+                    .parts
+                    .iter()
+                    .any(|part| matches!(part, WordPart::Lit(s) if s.contains("]=") || s.contains("]+=")));
+            }
+        }
+        match first.split_once('=') {
+            Some((name, _)) => is_name(name.strip_suffix('+').unwrap_or(name)),
+            None => false,
+        }
+    }
+
+    // name= or name+= right before the ( of an array assignment
+    fn is_array_target(&self) -> bool {
+        match self.parts.as_slice() {
+            [WordPart::Lit(s)] => match s.strip_suffix('=') {
+                Some(name) => is_name(name.strip_suffix('+').unwrap_or(name)),
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    // an approximation of the original text, for messages
+    pub fn source(&self) -> String {
+        parts_source(&self.parts)
+    }
+}
+
+fn parts_source(parts: &[WordPart]) -> String {
+    let mut result = String::new();
+    for part in parts {
+        match part {
+            WordPart::Lit(s) => result += s,
+            WordPart::Quoted(s) => result += &format!("'{}'", s),
+            WordPart::DQuoted(inner) => result += &format!("\"{}\"", parts_source(inner)),
+            WordPart::Var(name) => result += &format!("${}", name),
+            WordPart::CmdSubst(source) => result += &format!("$({})", source),
+            WordPart::Param(exp) => result += &format!("${{{}}}", exp.source),
+            WordPart::Arith(parts) => result += &format!("$(({}))", parts_source(parts)),
+            WordPart::ProcSubst { output, source } => {
+                result += &format!("{}({})", if *output { '>' } else { '<' }, source)
+            }
+            WordPart::Array(elements) => {
+                let elements: Vec<String> = elements.iter().map(Word::source).collect();
+                result += &format!("({})", elements.join(" "));
+            }
+        }
+    }
+    result
+}
+
+// An arithmetic expression is expanded like a double quoted string, the
+// quotes themselves are dropped
+fn arith_parts(source: &str) -> Result<Vec<WordPart>, ParseError> {
+    let mut lexer = Lexer {
+        chars: source.chars().collect(),
+        pos: 0,
+        extglob: false,
+    };
+    let mut word = Word::default();
+    while let Some(c) = lexer.peek() {
+        match c {
+            '"' => {
+                lexer.pos += 1;
+                let inner = lexer.double_quoted()?;
+                word.parts.extend(inner);
+            }
+            '$' | '`' | '\\' | '\'' => lexer.word_char(&mut word)?,
+            c => {
+                lexer.pos += 1;
+                word.push_lit(c);
+            }
+        }
+    }
+    Ok(word.parts)
+}
+
+// a here-document whose body follows the current line
+struct PendingHereDoc {
+    // index of the target word in the tokens
+    token: usize,
+    delimiter: String,
+    strip: bool,
+    quoted: bool,
+}
+
+impl PendingHereDoc {
+    fn unfinished(&self) -> ParseError {
+        ParseError::HereDoc {
+            delimiter: self.delimiter.clone(),
+            strip: self.strip,
+        }
+    }
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+    // ?(...), *(...), +(...), @(...) and !(...) are part of words
+    extglob: bool,
+}
+
+pub fn tokenize(line: &str, extglob: bool) -> Result<Vec<Token>, ParseError> {
+    let mut lexer = Lexer {
+        chars: line.chars().collect(),
+        pos: 0,
+        extglob,
+    };
+    lexer.tokens()
+}
+
+fn unexpected_eof(closing: char) -> ParseError {
+    ParseError::Incomplete(format!("unexpected EOF while looking for matching `{}'", closing))
+}
+
+// a valid variable name
+pub fn is_name(name: &str) -> bool {
+    name.starts_with(is_name_start) && name.chars().all(is_name_char)
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+impl Lexer {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn tokens(&mut self) -> Result<Vec<Token>, ParseError> {
+        let mut tokens = Vec::new();
+        let mut pending = Vec::new();
+        let mut word = Word::default();
+        // a word that is only quotes, like '', is still a word
+        let mut in_word = false;
+        while let Some(c) = self.peek() {
+            let operator = match c {
+                ' ' | '\t' => None,
+                '\n' => Some(Token::Newline),
+                '|' if self.peek_at(1) == Some('|') => {
+                    self.pos += 1;
+                    Some(Token::OrIf)
+                }
+                '|' => Some(Token::Pipe),
+                ';' if self.peek_at(1) == Some(';') => {
+                    self.pos += 1;
+                    Some(Token::DSemi)
+                }
+                ';' => Some(Token::Semi),
+                '(' if !in_word && self.peek_at(1) == Some('(') => {
+                    let start = self.pos;
+                    self.pos += 2;
+                    match self.arith_source()? {
+                        Some(source) => {
+                            tokens.push(Token::Arith(arith_parts(&source)?));
+                            continue;
+                        }
+                        // (( that starts nested subshells
+                        None => {
+                            self.pos = start;
+                            Some(Token::LParen)
+                        }
+                    }
+                }
+                '(' if in_word && word.is_array_target() => {
+                    self.pos += 1;
+                    let elements = self.array_elements()?;
+                    word.parts.push(WordPart::Array(elements));
+                    continue;
+                }
+                // ?(...), *(...), +(...), @(...) and !(...) are part of the word
+                '(' if self.extglob && in_word && matches!(word.parts.last(), Some(WordPart::Lit(s)) if s.ends_with(['?', '*', '+', '@', '!'])) => {
+                    self.ext_pattern(&mut word)?;
+                    continue;
+                }
+                '(' => Some(Token::LParen),
+                ')' => Some(Token::RParen),
+                '&' if self.peek_at(1) == Some('>') && self.peek_at(2) == Some('>') => {
+                    self.pos += 2;
+                    Some(Token::Redirect(None, RedirOp::AppendErr))
+                }
+                '&' if self.peek_at(1) == Some('>') => {
+                    self.pos += 1;
+                    Some(Token::Redirect(None, RedirOp::OutErr))
+                }
+                '&' if self.peek_at(1) == Some('&') => {
+                    self.pos += 1;
+                    Some(Token::AndIf)
+                }
+                '&' => Some(Token::Amp),
+                // process substitution is a word, or a part of one
+                '<' | '>' if self.peek_at(1) == Some('(') => {
+                    self.pos += 2;
+                    let source = self.until_closing_paren()?;
+                    word.parts.push(WordPart::ProcSubst { output: c == '>', source });
+                    in_word = true;
+                    continue;
+                }
+                '<' | '>' => {
+                    // digits right before the operator name the descriptor
+                    let fd = match word.literal() {
+                        Some(digits) if in_word && word.parts.len() == 1 && digits.chars().all(|d| d.is_ascii_digit()) => {
+                            let fd = digits
+                                .parse::<i32>()
+                                .map_err(|_| ParseError::Syntax(format!("bad file descriptor: {}", digits)))?;
+                            word = Word::default();
+                            in_word = false;
+                            Some(fd)
+                        }
+                        _ => None,
+                    };
+                    let op = match (c, self.peek_at(1)) {
+                        ('<', Some('<')) if self.peek_at(2) == Some('<') => {
+                            self.pos += 2;
+                            RedirOp::HereString
+                        }
+                        ('<', Some('<')) => {
+                            self.pos += 1;
+                            let strip = self.peek_at(1) == Some('-');
+                            if strip {
+                                self.pos += 1;
+                            }
+                            RedirOp::HereDoc(strip)
+                        }
+                        ('>', Some('&')) => {
+                            self.pos += 1;
+                            RedirOp::DupOut
+                        }
+                        ('>', Some('>')) => {
+                            self.pos += 1;
+                            RedirOp::Append
+                        }
+                        ('<', Some('>')) => {
+                            self.pos += 1;
+                            RedirOp::ReadWrite
+                        }
+                        ('<', Some('&')) => {
+                            self.pos += 1;
+                            RedirOp::DupIn
+                        }
+                        ('>', _) => RedirOp::Out,
+                        _ => RedirOp::In,
+                    };
+                    Some(Token::Redirect(fd, op))
+                }
+                '#' if !in_word => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                    continue;
+                }
+                _ => {
+                    self.word_char(&mut word)?;
+                    in_word = true;
+                    continue;
+                }
+            };
+            self.pos += 1;
+            if in_word {
+                tokens.push(Token::Word(std::mem::take(&mut word)));
+                in_word = false;
+            }
+            if let Some(token) = operator {
+                tokens.push(token);
+                match tokens.last() {
+                    // the delimiter is read right away, the body after the end of the line
+                    Some(&Token::Redirect(_, RedirOp::HereDoc(strip))) => {
+                        pending.push(self.here_delimiter(strip, tokens.len())?);
+                        tokens.push(Token::Word(Word::default()));
+                    }
+                    Some(Token::Newline) => {
+                        for here in pending.drain(..) {
+                            tokens[here.token] = Token::Word(self.here_body(&here)?);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if in_word {
+            tokens.push(Token::Word(word));
+        }
+        if let Some(here) = pending.first() {
+            return Err(here.unfinished());
+        }
+        Ok(tokens)
+    }
+
+    // the word after << up to a blank or an operator, `token` is where the body goes
+    fn here_delimiter(&mut self, strip: bool, token: usize) -> Result<PendingHereDoc, ParseError> {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.pos += 1;
+        }
+        let mut word = Word::default();
+        while self.peek().is_some_and(|c| !" \t\n|&;()<>".contains(c)) {
+            self.word_char(&mut word)?;
+        }
+        if word.parts.is_empty() {
+            let token = match self.peek() {
+                Some('\n') | None => "newline".to_string(),
+                Some(c) => c.to_string(),
+            };
+            return Err(ParseError::Syntax(format!("syntax error near unexpected token `{}'", token)));
+        }
+        Ok(PendingHereDoc {
+            token,
+            delimiter: word.literal().unwrap_or_else(|| word.source()),
+            strip,
+            // any quoting turns off expansions in the body
+            quoted: word.parts.iter().any(|part| !matches!(part, WordPart::Lit(_))),
+        })
+    }
+
+    // the lines up to the delimiter, at the start of a line
+    fn here_body(&mut self, here: &PendingHereDoc) -> Result<Word, ParseError> {
+        let mut body = String::new();
+        loop {
+            if self.peek().is_none() {
+                return Err(here.unfinished());
+            }
+            let start = self.pos;
+            while self.peek().is_some_and(|c| c != '\n') {
+                self.pos += 1;
+            }
+            let mut line: String = self.chars[start..self.pos].iter().collect();
+            // the newline
+            self.pos += 1;
+            if here.strip {
+                line = line.trim_start_matches('\t').to_string();
+            }
+            if line == here.delimiter {
+                break;
+            }
+            body += &line;
+            body.push('\n');
+        }
+        if here.quoted {
+            return Ok(Word {
+                parts: vec![WordPart::Quoted(body)],
+            });
+        }
+        let mut lexer = Lexer {
+            chars: body.chars().collect(),
+            pos: 0,
+            extglob: false,
+        };
+        let mut inner = Word::default();
+        while let Some(c) = lexer.peek() {
+            lexer.pos += 1;
+            match c {
+                // a backslash only escapes $, ` and itself, and joins lines
+                '\\' if matches!(lexer.peek(), Some('$' | '`' | '\\')) => {
+                    let escaped = lexer.chars[lexer.pos];
+                    lexer.pos += 1;
+                    inner.push_lit(escaped);
+                }
+                '\\' if lexer.peek() == Some('\n') => lexer.pos += 1,
+                '$' => match lexer.dollar()? {
+                    Some(part) => inner.parts.push(part),
+                    None => inner.push_lit('$'),
+                },
+                '`' => inner.parts.push(WordPart::CmdSubst(lexer.backquoted()?)),
+                c => inner.push_lit(c),
+            }
+        }
+        // expanded like a double quoted string
+        Ok(Word {
+            parts: vec![WordPart::DQuoted(inner.parts)],
+        })
+    }
+
+    // the words of name=(...) up to the closing parenthesis, on any number of lines
+    fn array_elements(&mut self) -> Result<Vec<Word>, ParseError> {
+        let mut elements = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err(unexpected_eof(')')),
+                Some(' ' | '\t' | '\n') => self.pos += 1,
+                Some(')') => {
+                    self.pos += 1;
+                    return Ok(elements);
+                }
+                Some('#') => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                Some(_) => {
+                    let mut word = Word::default();
+                    while self.peek().is_some_and(|c| !matches!(c, ' ' | '\t' | '\n' | '(' | ')')) {
+                        self.word_char(&mut word)?;
+                    }
+                    if self.peek() == Some('(') {
+                        return Err(ParseError::Syntax("syntax error near unexpected token `('".to_string()));
+                    }
+                    elements.push(word);
+                }
+            }
+        }
+    }
+
+    // the parentheses of an extended pattern and what is between them, where
+    // blanks and | don't end the word
+    fn ext_pattern(&mut self, word: &mut Word) -> Result<(), ParseError> {
+        let mut depth = 0;
+        while let Some(c) = self.peek() {
+            match c {
+                '(' | ')' => {
+                    self.pos += 1;
+                    word.push_lit(c);
+                    depth += if c == '(' { 1 } else { -1 };
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                _ => self.word_char(word)?,
+            }
+        }
+        Err(unexpected_eof(')'))
+    }
+
+    // consumes one unquoted character or quoted section of a word
+    fn word_char(&mut self, word: &mut Word) -> Result<(), ParseError> {
+        let c = self.chars[self.pos];
+        self.pos += 1;
+        match c {
+            '\\' => {
+                if let Some(next) = self.peek() {
+                    self.pos += 1;
+                    // a backslash before a newline joins the lines
+                    if next != '\n' {
+                        word.push_quoted(next);
+                    }
+                }
+            }
+            '\'' => {
+                let start = self.pos;
+                while self.peek().is_some_and(|c| c != '\'') {
+                    self.pos += 1;
+                }
+                if self.peek().is_none() {
+                    return Err(unexpected_eof('\''));
+                }
+                let quoted: String = self.chars[start..self.pos].iter().collect();
+                self.pos += 1;
+                word.parts.push(WordPart::Quoted(quoted));
+            }
+            '"' => {
+                let inner = self.double_quoted()?;
+                word.parts.push(WordPart::DQuoted(inner));
+            }
+            '$' => match self.dollar()? {
+                Some(part) => word.parts.push(part),
+                None => word.push_lit('$'),
+            },
+            '`' => word.parts.push(WordPart::CmdSubst(self.backquoted()?)),
+            c => word.push_lit(c),
+        }
+        Ok(())
+    }
+
+    // after the opening quote, up to and including the closing one
+    fn double_quoted(&mut self) -> Result<Vec<WordPart>, ParseError> {
+        let mut inner = Word::default();
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return Err(unexpected_eof('"')),
+            };
+            self.pos += 1;
+            match c {
+                '"' => return Ok(inner.parts),
+                // only these characters can be escaped inside double quotes
+                '\\' if matches!(self.peek(), Some('$' | '"' | '\\' | '`')) => {
+                    let escaped = self.chars[self.pos];
+                    self.pos += 1;
+                    inner.push_lit(escaped);
+                }
+                '\\' if self.peek() == Some('\n') => self.pos += 1,
+                '$' => match self.dollar()? {
+                    Some(part) => inner.parts.push(part),
+                    None => inner.push_lit('$'),
+                },
+                '`' => inner.parts.push(WordPart::CmdSubst(self.backquoted()?)),
+                c => inner.push_lit(c),
+            }
+        }
+    }
+
+    // after a $, None when it is just a dollar sign
+    fn dollar(&mut self) -> Result<Option<WordPart>, ParseError> {
+        match self.peek() {
+            Some('(') if self.peek_at(1) == Some('(') => {
+                let start = self.pos;
+                self.pos += 2;
+                match self.arith_source()? {
+                    Some(source) => Ok(Some(WordPart::Arith(arith_parts(&source)?))),
+                    // $( (...) ) is a command substitution
+                    None => {
+                        self.pos = start + 1;
+                        Ok(Some(WordPart::CmdSubst(self.until_closing_paren()?)))
+                    }
+                }
+            }
+            Some('(') => {
+                self.pos += 1;
+                Ok(Some(WordPart::CmdSubst(self.until_closing_paren()?)))
+            }
+            Some('{') => {
+                self.pos += 1;
+                Ok(Some(self.braced()?))
+            }
+            // positional and special parameters are a single character
+            Some(c) if c.is_ascii_digit() || "@*#?$!".contains(c) => {
+                self.pos += 1;
+                Ok(Some(WordPart::Var(c.to_string())))
+            }
+            Some(c) if is_name_start(c) => {
+                let start = self.pos;
+                while self.peek().is_some_and(is_name_char) {
+                    self.pos += 1;
+                }
+                Ok(Some(WordPart::Var(self.chars[start..self.pos].iter().collect())))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // after ${, up to and including the closing brace
+    fn braced(&mut self) -> Result<WordPart, ParseError> {
+        let start = self.pos;
+        // ${#} is the number of parameters, ${#name} a length
+        let length = self.peek() == Some('#') && !matches!(self.peek_at(1), None | Some('}'));
+        // ${!name[@]} are the keys of an array
+        let keys = self.peek() == Some('!') && self.peek_at(1).is_some_and(is_name_start);
+        if length || keys {
+            self.pos += 1;
+        }
+        let name = self.param_name();
+        if name.is_empty() {
+            return Err(self.bad_substitution(start));
+        }
+        let subscript = if is_name(&name) && self.peek() == Some('[') {
+            self.pos += 1;
+            let subscript = self.param_word("]")?;
+            self.pos += 1;
+            Some(subscript)
+        } else {
+            None
+        };
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Err(unexpected_eof('}')),
+        };
+        if keys {
+            let all = subscript.as_ref().and_then(Word::literal).is_some_and(|s| s == "@" || s == "*");
+            if c != '}' || !all {
+                return Err(self.bad_substitution(start));
+            }
+        }
+        if c == '}' {
+            self.pos += 1;
+            let op = match (length, keys) {
+                (true, _) => ParamOp::Length,
+                (_, true) => ParamOp::Keys,
+                _ if subscript.is_some() => ParamOp::Value,
+                _ => return Ok(WordPart::Var(name)),
+            };
+            let source = self.chars[start..self.pos - 1].iter().collect();
+            return Ok(WordPart::Param(Box::new(ParamExp { name, subscript, op, source })));
+        }
+        if length {
+            return Err(self.bad_substitution(start));
+        }
+        self.pos += 1;
+        let colon = c == ':' && matches!(self.peek(), Some('-' | '=' | '?' | '+'));
+        let c = if colon {
+            self.pos += 1;
+            self.chars[self.pos - 1]
+        } else {
+            c
+        };
+        // a doubled operator character selects the longest or global form
+        let doubled = matches!(c, '#' | '%' | '/' | '^' | ',') && self.peek() == Some(c);
+        if doubled {
+            self.pos += 1;
+        }
+        let op = match c {
+            '-' => ParamOp::Default { colon, word: self.param_word("}")? },
+            '=' => ParamOp::Assign { colon, word: self.param_word("}")? },
+            '?' => ParamOp::Error { colon, word: self.param_word("}")? },
+            '+' => ParamOp::Alternate { colon, word: self.param_word("}")? },
+            '#' => ParamOp::TrimPrefix { longest: doubled, pattern: self.param_word("}")? },
+            '%' => ParamOp::TrimSuffix { longest: doubled, pattern: self.param_word("}")? },
+            '/' => {
+                let anchor = match self.peek() {
+                    Some(c) if !doubled && (c == '#' || c == '%') => {
+                        self.pos += 1;
+                        Some(c)
+                    }
+                    _ => None,
+                };
+                let pattern = self.param_word("/}")?;
+                let replacement = if self.peek() == Some('/') {
+                    self.pos += 1;
+                    self.param_word("}")?
+                } else {
+                    Word::default()
+                };
+                ParamOp::Replace { all: doubled, anchor, pattern, replacement }
+            }
+            ':' => {
+                let offset = self.param_word(":}")?;
+                let length = if self.peek() == Some(':') {
+                    self.pos += 1;
+                    Some(self.param_word("}")?)
+                } else {
+                    None
+                };
+                ParamOp::Substring { offset, length }
+            }
+            '^' => ParamOp::Upper { all: doubled },
+            ',' => ParamOp::Lower { all: doubled },
+            _ => return Err(self.bad_substitution(start)),
+        };
+        match self.peek() {
+            Some('}') => self.pos += 1,
+            Some(_) => return Err(self.bad_substitution(start)),
+            None => return Err(unexpected_eof('}')),
+        }
+        let source = self.chars[start..self.pos - 1].iter().collect();
+        Ok(WordPart::Param(Box::new(ParamExp { name, subscript, op, source })))
+    }
+
+    // a name, a positional parameter of any number of digits or a special parameter
+    fn param_name(&mut self) -> String {
+        let start = self.pos;
+        match self.peek() {
+            Some(c) if is_name_start(c) => {
+                while self.peek().is_some_and(is_name_char) {
+                    self.pos += 1;
+                }
+            }
+            Some(c) if c.is_ascii_digit() => {
+                while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                    self.pos += 1;
+                }
+            }
+            Some(c) if "@*#?$!".contains(c) => self.pos += 1,
+            _ => {}
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    // the word of an operator, blanks included, up to one of `stop` outside quotes
+    fn param_word(&mut self, stop: &str) -> Result<Word, ParseError> {
+        let mut word = Word::default();
+        while let Some(c) = self.peek() {
+            if stop.contains(c) {
+                return Ok(word);
+            }
+            self.word_char(&mut word)?;
+        }
+        Err(unexpected_eof('}'))
+    }
+
+    fn bad_substitution(&mut self, start: usize) -> ParseError {
+        while self.peek().is_some_and(|c| c != '}') {
+            self.pos += 1;
+        }
+        let source: String = self.chars[start..self.pos].iter().collect();
+        ParseError::Syntax(format!("${{{}}}: bad substitution", source))
+    }
+
+    // the legacy form of $(...), a backslash only escapes $, ` and itself
+    fn backquoted(&mut self) -> Result<String, ParseError> {
+        let mut source = String::new();
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return Err(unexpected_eof('`')),
+            };
+            self.pos += 1;
+            match c {
+                '`' => return Ok(source),
+                '\\' if matches!(self.peek(), Some('$' | '`' | '\\')) => {
+                    source.push(self.chars[self.pos]);
+                    self.pos += 1;
+                }
+                c => source.push(c),
+            }
+        }
+    }
+
+    // after ((, the expression up to the matching )), None when a single )
+    // closes the first parenthesis
+    fn arith_source(&mut self) -> Result<Option<String>, ParseError> {
+        let start = self.pos;
+        let mut depth = 0;
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                '\\' => self.pos += 1,
+                '(' => depth += 1,
+                ')' if depth > 0 => depth -= 1,
+                ')' if self.peek() == Some(')') => {
+                    let source = self.chars[start..self.pos - 1].iter().collect();
+                    self.pos += 1;
+                    return Ok(Some(source));
+                }
+                ')' => return Ok(None),
+                _ => {}
+            }
+        }
+        Err(unexpected_eof(')'))
+    }
+
+    // source up to the matching ), skipping parentheses inside quotes
+    fn until_closing_paren(&mut self) -> Result<String, ParseError> {
+        let start = self.pos;
+        let mut depth = 1;
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                '\\' => self.pos += 1,
+                '\'' => {
+                    while self.peek().is_some_and(|c| c != '\'') {
+                        self.pos += 1;
+                    }
+                    self.pos += 1;
+                }
+                '"' => {
+                    while let Some(c) = self.peek() {
+                        self.pos += 1;
+                        match c {
+                            '\\' => self.pos += 1,
+                            '"' => break,
+                            _ => {}
+                        }
+                    }
+                }
+                '`' => {
+                    while self.peek().is_some_and(|c| c != '`') {
+                        self.pos += 1;
+                    }
+                    self.pos += 1;
+                }
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(self.chars[start..self.pos - 1].iter().collect());
+                    }
+                }
+                _ => {}
+            }
+        }
+        Err(unexpected_eof(')'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lit(text: &str) -> WordPart {
+        WordPart::Lit(text.to_string())
+    }
+
+    fn word(parts: Vec<WordPart>) -> Token {
+        Token::Word(Word { parts })
+    }
+
+    #[test]
+    fn operators_and_words() {
+        let tokens = tokenize("a|b && c;d 2>&1 >>f\n", false).unwrap();
+        assert_eq!(
+            tokens,
+            [
+                word(vec![lit("a")]),
+                Token::Pipe,
+                word(vec![lit("b")]),
+                Token::AndIf,
+                word(vec![lit("c")]),
+                Token::Semi,
+                word(vec![lit("d")]),
+                Token::Redirect(Some(2), RedirOp::DupOut),
+                word(vec![lit("1")]),
+                Token::Redirect(None, RedirOp::Append),
+                word(vec![lit("f")]),
+                Token::Newline,
+            ]
+        );
+        // a comment only starts a word
+        assert_eq!(tokenize("a#b # c", false).unwrap(), [word(vec![lit("a#b")])]);
+    }
+
+    #[test]
+    fn quoting() {
+        let tokens = tokenize(r#"'a $b' "c $d" e\ f"#, false).unwrap();
+        assert_eq!(
+            tokens,
+            [
+                word(vec![WordPart::Quoted("a $b".to_string())]),
+                word(vec![WordPart::DQuoted(vec![lit("c "), WordPart::Var("d".to_string())])]),
+                word(vec![lit("e"), WordPart::Quoted(" ".to_string()), lit("f")]),
+            ]
+        );
+        // an empty quote is still a word
+        assert_eq!(tokenize("''", false).unwrap(), [word(vec![WordPart::Quoted(String::new())])]);
+        assert!(matches!(tokenize("'a", false), Err(ParseError::Incomplete(_))));
+        assert!(matches!(tokenize("\"a", false), Err(ParseError::Incomplete(_))));
+        assert!(matches!(tokenize("$(a", false), Err(ParseError::Incomplete(_))));
+    }
+
+    #[test]
+    fn here_documents() {
+        let tokens = tokenize("cat <<E; b\nx $y\nE\n", false).unwrap();
+        assert_eq!(
+            tokens,
+            [
+                word(vec![lit("cat")]),
+                Token::Redirect(None, RedirOp::HereDoc(false)),
+                word(vec![WordPart::DQuoted(vec![lit("x "), WordPart::Var("y".to_string()), lit("\n")])]),
+                Token::Semi,
+                word(vec![lit("b")]),
+                Token::Newline,
+            ]
+        );
+        // a quoted delimiter turns off expansions, <<- strips leading tabs
+        let tokens = tokenize("cat <<-'E'\n\tx $y\n\tE\n", false).unwrap();
+        assert_eq!(tokens[2], word(vec![WordPart::Quoted("x $y\n".to_string())]));
+        assert_eq!(
+            tokenize("cat <<E\nx\n", false),
+            Err(ParseError::HereDoc {
+                delimiter: "E".to_string(),
+                strip: false
+            })
+        );
+        assert!(matches!(tokenize("cat <<\n", false), Err(ParseError::Syntax(_))));
+    }
+
+    #[test]
+    fn extended_patterns_need_extglob() {
+        assert_eq!(tokenize("@(a|b)", true).unwrap(), [word(vec![lit("@(a|b)")])]);
+        assert_eq!(
+            tokenize("@(a)", false).unwrap(),
+            [word(vec![lit("@")]), Token::LParen, word(vec![lit("a")]), Token::RParen]
+        );
+    }
+}
@@ -1,37 +1,38 @@
-use super::MyShell;
+use super::arrays::split_subscript;
+use super::lexer::is_name;
+use super::parser::LineBuffer;
+use super::{Jump, MyShell};
+use super::descriptors::private_file;
+use super::pattern::GLOB_OPTIONS;
 use super::utils::{ioe_descriptors_to_files, writex};
 use std::io::{BufReader, BufRead};
-use std::{env, fs::File, process};
+use std::{env, fs::File, mem, process};
 
 impl MyShell {
-    pub fn merrno(&mut self, command: &Vec<String>, ioe_descs: [i32; 3]) -> i32 {
+    pub fn merrno(&mut self, command: &[String], ioe_descs: [i32; 3]) -> i32 {
         let (_, fout, ferr) = unsafe { ioe_descriptors_to_files(&ioe_descs) };
-        if command.len() == 2 {
-            if command[1] == "-h" || command[1] == "--help" {
-                writex(
-                    &fout,
-                    "Get status code of last command\n Usage: \n    merrno [-h|--help]\n",
-                );
-                return 0;
-            }
+        if command.len() == 2 && (command[1] == "-h" || command[1] == "--help") {
+            writex(
+                &fout,
+                "Get status code of last command\n Usage: \n    merrno [-h|--help]\n",
+            );
+            return 0;
         }
         if command.len() >= 2 {
             writex(&ferr, "merrno: too many arguments\n");
             return 1;
         }
         writex(&fout, &(self.last_exit_code.to_string() + "\n"));
-        return 0;
+        0
     }
-    pub fn mpwd(&mut self, command: &Vec<String>, ioe_descs: [i32; 3]) -> i32 {
+    pub fn mpwd(&mut self, command: &[String], ioe_descs: [i32; 3]) -> i32 {
         let (_, fout, ferr) = unsafe { ioe_descriptors_to_files(&ioe_descs) };
-        if command.len() == 2 {
-            if command[1] == "-h" || command[1] == "--help" {
-                writex(
-                    &fout,
-                    "Get current directory\n Usage: \n    mpwd [-h|--help]\n",
-                );
-                return 0;
-            }
+        if command.len() == 2 && (command[1] == "-h" || command[1] == "--help") {
+            writex(
+                &fout,
+                "Get current directory\n Usage: \n    mpwd [-h|--help]\n",
+            );
+            return 0;
         }
         if command.len() >= 2 {
             writex(&ferr, "mpwd: too many arguments\n");
@@ -48,9 +49,9 @@ impl MyShell {
         // TODO: explore why
         println!("{}", curdir);
         // MyShell::writex(&fout, &curdir);
-        return 0;
+        0
     }
-    pub fn mcd(&mut self, command: &Vec<String>, ioe_descs: [i32; 3]) -> i32 {
+    pub fn mcd(&mut self, command: &[String], ioe_descs: [i32; 3]) -> i32 {
         let (_, fout, ferr) = unsafe { ioe_descriptors_to_files(&ioe_descs) };
         if command.len() > 2 {
             writex(&ferr, "mcd: too many arguments\n");
@@ -61,7 +62,7 @@ impl MyShell {
             cd_path = match env::var("HOME") {
                 Ok(val) => val,
                 Err(err) => {
-                    writex(&ferr, &format!("mcd: {}\n", err.to_string()));
+                    writex(&ferr, &format!("mcd: {}\n", err));
                     return 2;
                 }
             }
@@ -75,64 +76,82 @@ impl MyShell {
             }
             cd_path = command[1].clone();
         }
+        let previous = env::current_dir();
         match env::set_current_dir(&cd_path) {
-            Ok(_) => return 0,
+            Ok(_) => {
+                // for ~- and ~+
+                if let Ok(previous) = previous {
+                    env::set_var("OLDPWD", previous);
+                }
+                if let Ok(current) = env::current_dir() {
+                    env::set_var("PWD", current);
+                }
+                0
+            }
             Err(err) => {
-                writex(&ferr, &format!("mcd: {}\n", err.to_string()));
-                return 3;
+                writex(&ferr, &format!("mcd: {}\n", err));
+                3
             }
         }
     }
-    pub fn execute_script(&mut self, command: &Vec<String>, ioe_descs: [i32; 3]) -> i32 {
+    pub fn execute_script(&mut self, command: &[String], ioe_descs: [i32; 3]) -> i32 {
         let (_, _, ferr) = unsafe { ioe_descriptors_to_files(&ioe_descs) };
-        if command.len() != 2 {
-            writex(&ferr, ".: bad number of arguments");
+        if command.len() < 2 {
+            writex(&ferr, ".: filename argument required\n");
             return 1;
         }
 
         let file = match File::open(&command[1]) {
-            Ok(f) => f,
+            Ok(f) => private_file(f),
             Err(err) => {
-                writex(&ferr, &format!(".: {}", err.to_string()));
+                writex(&ferr, &format!(".: {}", err));
                 return 2;
             }
         };
-        let reader = BufReader::new(file);
+        // arguments after the file name are its positional parameters while it runs
+        let saved = (command.len() > 2).then(|| mem::replace(&mut self.positional, command[2..].to_vec()));
+        let status = self.run_lines(BufReader::new(file));
+        if let Some(positional) = saved {
+            self.positional = positional;
+        }
+        status
+    }
+    fn run_lines(&mut self, reader: BufReader<File>) -> i32 {
+        let mut lines = LineBuffer::default();
         for line in reader.lines() {
-            let mut line = match line {
+            let line = match line {
                 Ok(l) => l,
                 Err(err) => {
-                    eprintln!("myshell: {}", err.to_string());
+                    eprintln!("myshell: {}", err);
                     return 1;
                 }
             };
-            self.last_exit_code = self.interpret_line(&mut line);
+            // compound commands span several lines
+            if let Some(input) = lines.push(&line, self.glob_options.extglob) {
+                self.last_exit_code = self.interpret_line(input);
+            }
             if self.time_to_exit {
-                break;
+                return self.last_exit_code;
             }
         }
-        return self.last_exit_code;
+        if !lines.is_empty() {
+            // the file ended inside a command, this reports the error
+            self.last_exit_code = self.interpret_line(lines.take());
+        }
+        self.last_exit_code
     }
-    pub fn mecho(&mut self, command: &Vec<String>, ioe_descs: [i32; 3]) -> i32 {
+    pub fn mecho(&mut self, command: &[String], ioe_descs: [i32; 3]) -> i32 {
         let (_, fout, _) = unsafe { ioe_descriptors_to_files(&ioe_descs) };
-        if command.len() == 2 {
-            if command[1] == "-h" || command[1] == "--help" {
-                writex(&fout, "Print text and substite variables\n    Usage: mecho [-h|--help] [text|$<var_name>] ...\n");
-                return 0;
-            }
-        }
-        let mut output: String = String::new();
-        for i in 1..command.len() {
-            if i != 1 {
-                output += " ";
-            }
-            output += &command[i];
+        if command.len() == 2 && (command[1] == "-h" || command[1] == "--help") {
+            writex(&fout, "Print text and substite variables\n    Usage: mecho [-h|--help] [text|$<var_name>] ...\n");
+            return 0;
         }
+        let mut output: String = command[1..].join(" ");
         output += "\n";
         writex(&fout, &output);
-        return 0;
+        0
     }
-    pub fn mexport(&mut self, command: &Vec<String>, ioe_descs: [i32; 3]) -> i32 {
+    pub fn mexport(&mut self, command: &[String], ioe_descs: [i32; 3]) -> i32 {
         let (_, _, ferr) = unsafe { ioe_descriptors_to_files(&ioe_descs) };
         if command.len() != 2 {
             writex(&ferr, "mexport: bad number of arguments\n");
@@ -144,13 +163,13 @@ impl MyShell {
             return 2;
         }
         env::set_var(splitted[0], splitted[1]);
-        return 1;
+        0
     }
-    // pub fn alias(&mut self, command: &Vec<String>, ioe_descs: [i32; 3]) -> i32 {
+    // pub fn alias(&mut self, command: &[String], ioe_descs: [i32; 3]) -> i32 {
     //     println!("alias called!");
     //     return 0;
     // }
-    pub fn mexit(&mut self, command: &Vec<String>, ioe_descs: [i32; 3]) -> i32 {
+    pub fn mexit(&mut self, command: &[String], ioe_descs: [i32; 3]) -> i32 {
         let (_, fout, ferr) = unsafe { ioe_descriptors_to_files(&ioe_descs) };
         let mut status: i32 = 0;
         if command.len() == 2 {
@@ -174,23 +193,288 @@ impl MyShell {
             return 2;
         }
         self.time_to_exit = true;
-        return status;
+        status
     }
-    pub fn set_local_variable(&mut self, command: &Vec<String>, ioe_descs: [i32; 3]) -> i32 {
+    // break [n] and continue [n] leave or restart the n-th enclosing loop
+    pub fn loop_jump(&mut self, command: &[String], ioe_descs: [i32; 3]) -> i32 {
+        let (_, _, ferr) = unsafe { ioe_descriptors_to_files(&ioe_descs) };
+        if command.len() > 2 {
+            writex(&ferr, &format!("{}: too many arguments\n", command[0]));
+            return 1;
+        }
+        let n: u32 = match command.get(1).map(|n| n.parse()) {
+            None => 1,
+            Some(Ok(n)) if n > 0 => n,
+            Some(_) => {
+                writex(&ferr, &format!("{}: {}: loop count out of range\n", command[0], command[1]));
+                return 1;
+            }
+        };
+        if self.loop_depth == 0 {
+            writex(&ferr, &format!("{}: only meaningful in a `for', `while', or `until' loop\n", command[0]));
+            return 0;
+        }
+        let n = n.min(self.loop_depth);
+        self.jump = Some(if command[0] == "break" { Jump::Break(n) } else { Jump::Continue(n) });
+        0
+    }
+    // local name[=value] ... keeps the changes to the current function call
+    pub fn local(&mut self, command: &[String], ioe_descs: [i32; 3]) -> i32 {
+        let (_, _, ferr) = unsafe { ioe_descriptors_to_files(&ioe_descs) };
+        if self.local_frames.is_empty() {
+            writex(&ferr, "local: can only be used in a function\n");
+            return 1;
+        }
+        let mut status = 0;
+        for arg in &command[1..] {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.as_str(), None),
+            };
+            if !is_name(name) {
+                writex(&ferr, &format!("local: `{}': not a valid identifier\n", arg));
+                status = 1;
+                continue;
+            }
+            let previous = self.local_vars.get(name).cloned();
+            if let Some(frame) = self.local_frames.last_mut() {
+                // the value from before the first `local` is the one to restore
+                frame.entry(name.to_string()).or_insert(previous);
+            }
+            match value {
+                Some(value) => self.local_vars.insert(name.to_string(), value.to_string()),
+                None => self.local_vars.remove(name),
+            };
+        }
+        status
+    }
+    pub fn mreturn(&mut self, command: &[String], ioe_descs: [i32; 3]) -> i32 {
+        let (_, _, ferr) = unsafe { ioe_descriptors_to_files(&ioe_descs) };
+        if self.local_frames.is_empty() {
+            writex(&ferr, "return: can only `return' from a function\n");
+            return 1;
+        }
+        if command.len() > 2 {
+            writex(&ferr, "return: too many arguments\n");
+            return 1;
+        }
+        let status = match command.get(1).map(|n| n.parse::<i32>()) {
+            None => self.last_exit_code,
+            Some(Ok(n)) => n & 0xff,
+            Some(Err(_)) => {
+                writex(&ferr, &format!("return: {}: numeric argument required\n", command[1]));
+                2
+            }
+        };
+        self.jump = Some(Jump::Return(status));
+        status
+    }
+    pub fn shift(&mut self, command: &[String], ioe_descs: [i32; 3]) -> i32 {
+        let (_, _, ferr) = unsafe { ioe_descriptors_to_files(&ioe_descs) };
+        if command.len() > 2 {
+            writex(&ferr, "shift: too many arguments\n");
+            return 1;
+        }
+        let n: usize = match command.get(1).map(|n| n.parse()) {
+            None => 1,
+            Some(Ok(n)) => n,
+            Some(Err(_)) => {
+                writex(&ferr, &format!("shift: {}: numeric argument required\n", command[1]));
+                return 1;
+            }
+        };
+        if n > self.positional.len() {
+            writex(&ferr, &format!("shift: {}: shift count out of range\n", n));
+            return 1;
+        }
+        self.positional.drain(..n);
+        0
+    }
+    // let expression ... succeeds when the last expression is not zero
+    pub fn mlet(&mut self, command: &[String], ioe_descs: [i32; 3]) -> i32 {
+        let (_, _, ferr) = unsafe { ioe_descriptors_to_files(&ioe_descs) };
+        if command.len() < 2 {
+            writex(&ferr, "let: expression expected\n");
+            return 1;
+        }
+        let mut value = 0;
+        for expr in &command[1..] {
+            value = match self.eval_arith(expr) {
+                Ok(value) => value,
+                Err(err) => {
+                    writex(&ferr, &format!("let: {}\n", err));
+                    return 1;
+                }
+            };
+        }
+        (value == 0) as i32
+    }
+    // shopt [-s|-u] [-q] [name ...] sets, unsets or shows the glob options
+    pub fn shopt(&mut self, command: &[String], ioe_descs: [i32; 3]) -> i32 {
+        let (_, fout, ferr) = unsafe { ioe_descriptors_to_files(&ioe_descs) };
+        let mut value = None;
+        let mut quiet = false;
+        let mut args = &command[1..];
+        while let Some(flag) = args.first().filter(|arg| arg.starts_with('-')) {
+            match flag.as_str() {
+                "-s" => value = Some(true),
+                "-u" => value = Some(false),
+                "-q" => quiet = true,
+                _ => {
+                    writex(&ferr, &format!("shopt: {}: invalid option\nUsage: shopt [-s|-u] [-q] [optname ...]\n", flag));
+                    return 2;
+                }
+            }
+            args = &args[1..];
+        }
+        let names: Vec<&str> = if args.is_empty() {
+            GLOB_OPTIONS.to_vec()
+        } else {
+            args.iter().map(String::as_str).collect()
+        };
+        let mut status = 0;
+        for name in names {
+            let option = match self.glob_options.option(name) {
+                Some(option) => option,
+                None => {
+                    writex(&ferr, &format!("shopt: {}: invalid shell option name\n", name));
+                    status = 1;
+                    continue;
+                }
+            };
+            match value {
+                // without names, -s and -u list the options that are on or off
+                Some(value) if !args.is_empty() => *option = value,
+                Some(value) if *option != value => {}
+                _ => {
+                    if !*option {
+                        status = 1;
+                    }
+                    if !quiet {
+                        writex(&fout, &format!("{:<15}\t{}\n", name, if *option { "on" } else { "off" }));
+                    }
+                }
+            }
+        }
+        // listing with -s or -u succeeds
+        if value.is_some() && args.is_empty() {
+            return 0;
+        }
+        status
+    }
+    // declare [-a|-A] [-p] [name[=value] ...], -a and -A make arrays
+    pub fn declare(&mut self, command: &[String], ioe_descs: [i32; 3]) -> i32 {
+        let (_, fout, ferr) = unsafe { ioe_descriptors_to_files(&ioe_descs) };
+        let mut kind = None;
+        let mut print = false;
+        let mut args = &command[1..];
+        while let Some(flag) = args.first().filter(|arg| arg.starts_with('-')) {
+            for c in flag.chars().skip(1) {
+                match c {
+                    'a' | 'A' => kind = Some(c == 'A'),
+                    'p' => print = true,
+                    _ => {
+                        writex(&ferr, &format!("declare: -{}: invalid option\nUsage: declare [-aAp] [name[=value] ...]\n", c));
+                        return 2;
+                    }
+                }
+            }
+            args = &args[1..];
+        }
+        if print || args.is_empty() {
+            let mut names: Vec<String> = args.to_vec();
+            if names.is_empty() {
+                names = self.arrays.keys().chain(self.local_vars.keys()).cloned().collect();
+                names.sort();
+            }
+            let mut status = 0;
+            for name in names {
+                match self.describe_variable(&name) {
+                    Some(description) => writex(&fout, &format!("{}\n", description)),
+                    None => {
+                        writex(&ferr, &format!("declare: {}: not found\n", name));
+                        status = 1;
+                    }
+                }
+            }
+            return status;
+        }
+        let mut status = 0;
+        for arg in args {
+            let name = arg.split(['=', '[']).next().unwrap_or_default();
+            let name = name.strip_suffix('+').unwrap_or(name);
+            if !is_name(name) {
+                writex(&ferr, &format!("declare: `{}': not a valid identifier\n", arg));
+                status = 1;
+                continue;
+            }
+            let result = match kind {
+                Some(assoc) => self.declare_array(name, assoc),
+                None => Ok(()),
+            };
+            let result = match result {
+                Ok(()) if arg.contains('=') => self.assign(arg),
+                result => result,
+            };
+            if let Err(err) = result {
+                writex(&ferr, &format!("declare: {}\n", err));
+                status = 1;
+            }
+        }
+        status
+    }
+    // unset [-f|-v] name ..., name[subscript] removes an element of an array
+    pub fn unset(&mut self, command: &[String], ioe_descs: [i32; 3]) -> i32 {
+        let (_, _, ferr) = unsafe { ioe_descriptors_to_files(&ioe_descs) };
+        let mut functions = false;
+        let mut args = &command[1..];
+        while let Some(flag) = args.first().filter(|arg| arg.starts_with('-')) {
+            match flag.as_str() {
+                "-f" => functions = true,
+                "-v" => functions = false,
+                _ => {
+                    writex(&ferr, &format!("unset: {}: invalid option\nUsage: unset [-f] [-v] [name ...]\n", flag));
+                    return 2;
+                }
+            }
+            args = &args[1..];
+        }
+        let mut status = 0;
+        for arg in args {
+            if functions {
+                self.functions.remove(arg);
+                continue;
+            }
+            let result = match split_subscript(arg) {
+                Some((name, subscript)) => self.unset_element(name, subscript),
+                None if is_name(arg) => {
+                    self.arrays.remove(arg.as_str());
+                    self.local_vars.remove(arg.as_str());
+                    env::remove_var(arg);
+                    Ok(())
+                }
+                None => Err(format!("`{}': not a valid identifier", arg)),
+            };
+            if let Err(err) = result {
+                writex(&ferr, &format!("unset: {}\n", err));
+                status = 1;
+            }
+        }
+        status
+    }
+    pub fn set_local_variable(&mut self, command: &[String], ioe_descs: [i32; 3]) -> i32 {
 
         let (_, _, ferr) = unsafe { ioe_descriptors_to_files(&ioe_descs) };
-        let splitted: Vec<&str> = command[0].split("=").collect();
-        if splitted.len() != 2 {
-            writex(&ferr, "myshell: syntax error\n");
+        if let Err(err) = self.assign(&command[0]) {
+            writex(&ferr, &format!("myshell: {}\n", err));
             return 1;
         }
-        self.local_vars.insert(splitted[0].to_string(), splitted[1].to_string());
-        return 0;
+        0
     }
 
 
     
-    pub fn call_mcommand(&mut self, command: &Vec<String>, ioe_descs: [i32; 3]) -> i32 {
+    pub fn call_mcommand(&mut self, command: &[String], ioe_descs: [i32; 3]) -> i32 {
         // TODO:: look awful
         if command[0] == "merrno" {
             return self.merrno(command, ioe_descs);
@@ -208,7 +492,23 @@ impl MyShell {
             // return self.alias(command, ioe_descs);
         } else if command[0] == "mexit" {
             return self.mexit(command, ioe_descs);
+        } else if command[0] == "break" || command[0] == "continue" {
+            return self.loop_jump(command, ioe_descs);
+        } else if command[0] == "local" {
+            return self.local(command, ioe_descs);
+        } else if command[0] == "return" {
+            return self.mreturn(command, ioe_descs);
+        } else if command[0] == "let" {
+            return self.mlet(command, ioe_descs);
+        } else if command[0] == "shift" {
+            return self.shift(command, ioe_descs);
+        } else if command[0] == "shopt" {
+            return self.shopt(command, ioe_descs);
+        } else if command[0] == "declare" {
+            return self.declare(command, ioe_descs);
+        } else if command[0] == "unset" {
+            return self.unset(command, ioe_descs);
         }
-        return 0;
+        0
     }
 }
@@ -0,0 +1,670 @@
+pub use super::lexer::ParseError;
+use super::lexer::{is_name, tokenize, RedirOp, Token, Word};
+use std::rc::Rc;
+
+// Syntax tree of a command line:
+//   list          := and_or ((';' | '&' | newline) and_or)*
+//   and_or        := pipe_sequence (('&&' | '||') pipe_sequence)*
+//   pipe_sequence := command ('|' command)*
+//   command       := (word | redirect)+ | compound redirect* | function
+//   compound      := if | while | until | for | case | '(' list ')' | '{' list '}'
+//   function      := (name '(' ')' | 'function' name ['(' ')']) '{' list '}' redirect*
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Redirect {
+    // None for the default descriptor of the operator
+    pub fd: Option<i32>,
+    pub op: RedirOp,
+    pub target: Word,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SimpleCommand {
+    pub words: Vec<Word>,
+    // in the order they appear, later ones win
+    pub redirects: Vec<Redirect>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IfClause {
+    // condition and body of the if and every elif
+    pub branches: Vec<(List, List)>,
+    pub else_body: Option<List>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoopClause {
+    pub condition: List,
+    pub body: List,
+    // loops while the condition fails
+    pub until: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ForClause {
+    pub name: String,
+    // None without `in`, then the positional parameters are used
+    pub words: Option<Vec<Word>>,
+    pub body: List,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaseItem {
+    pub patterns: Vec<Word>,
+    pub body: List,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaseClause {
+    pub word: Word,
+    pub items: Vec<CaseItem>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompoundCommand {
+    If(IfClause),
+    Loop(LoopClause),
+    For(ForClause),
+    Case(CaseClause),
+    // ((expression))
+    Arith(Word),
+    // ( list ), runs in a copy of the shell
+    Subshell(List),
+    // { list; }
+    Group(List),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionDef {
+    pub name: String,
+    pub body: List,
+    // applied every time the function is called
+    pub redirects: Vec<Redirect>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Simple(SimpleCommand),
+    Compound(CompoundCommand, Vec<Redirect>),
+    // running the definition stores the function
+    Function(Rc<FunctionDef>),
+}
+
+impl Command {
+    pub fn redirects(&self) -> &[Redirect] {
+        match self {
+            Command::Simple(command) => &command.redirects,
+            Command::Compound(_, redirects) => redirects,
+            Command::Function(_) => &[],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PipeSequence {
+    pub commands: Vec<Command>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AndOrOp {
+    And,
+    Or,
+}
+
+// the pipelines after the first one run depending on the status so far
+#[derive(Clone, Debug, PartialEq)]
+pub struct AndOr {
+    pub first: PipeSequence,
+    pub rest: Vec<(AndOrOp, PipeSequence)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListItem {
+    pub and_or: AndOr,
+    // terminated with &
+    pub background: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct List {
+    pub items: Vec<ListItem>,
+}
+
+// words that close a compound command, they can't start one
+const CLOSING_WORDS: [&str; 8] = ["then", "elif", "else", "fi", "do", "done", "esac", "}"];
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+// with `extglob` extended patterns are read as parts of words
+pub fn parse(source: &str, extglob: bool) -> Result<List, ParseError> {
+    parse_tokens(&tokenize(source, extglob)?)
+}
+
+fn parse_tokens(tokens: &[Token]) -> Result<List, ParseError> {
+    let mut parser = Parser { tokens, pos: 0 };
+    parser.list(&[])
+}
+
+// complete commands gathered by a LineBuffer, and their source
+pub struct Input {
+    pub source: String,
+    pub list: Result<List, ParseError>,
+}
+
+// Counts the compound commands and subshells left open by the tokens so far.
+// Like for the parser, reserved words only count in command position.
+#[derive(Default)]
+struct Nesting {
+    depth: i64,
+    // the next word is an argument, it can't be a reserved word
+    argument: bool,
+    // after `case` until `in`
+    case_word: bool,
+    // case patterns up to their `)`
+    patterns: bool,
+}
+
+impl Nesting {
+    fn scan(&mut self, token: &Token) {
+        let argument = std::mem::take(&mut self.argument);
+        match token {
+            Token::Word(word) if self.patterns && word.is_reserved("esac") => {
+                self.depth -= 1;
+                self.patterns = false;
+            }
+            Token::Word(_) if self.patterns => {}
+            Token::Word(word) => {
+                let reserved = |names: &[&str]| names.iter().any(|name| word.is_reserved(name));
+                if self.case_word && word.is_reserved("in") {
+                    self.case_word = false;
+                    self.patterns = true;
+                } else if !argument && reserved(&["fi", "done", "esac", "}"]) {
+                    self.depth -= 1;
+                } else if !argument && reserved(&["if", "while", "until", "for", "case", "{"]) {
+                    self.depth += 1;
+                    self.case_word = word.is_reserved("case");
+                }
+                // these are followed by a command
+                self.argument = argument || !reserved(&["if", "while", "until", "then", "do", "else", "elif", "{"]);
+            }
+            // the optional `(` before the patterns
+            Token::LParen if self.patterns => {}
+            Token::RParen if self.patterns => self.patterns = false,
+            Token::LParen => self.depth += 1,
+            Token::RParen => self.depth -= 1,
+            Token::DSemi => self.patterns = true,
+            Token::Redirect(..) | Token::Arith(_) => self.argument = true,
+            _ => {}
+        }
+    }
+}
+
+// Gathers input lines until they form complete commands, so a compound
+// command, a quote or a here-document can span several lines. Each line is
+// tokenized once and the tokens are parsed again only when the line may
+// complete them.
+#[derive(Default)]
+pub struct LineBuffer {
+    source: String,
+    tokens: Vec<Token>,
+    // how much of the source the tokens cover, always whole lines
+    lexed: usize,
+    lexed_tokens: usize,
+    // the rest of the source is the body of a here-document with this delimiter
+    here_doc: Option<(String, bool)>,
+    // the rest of the source is inside a quote or a substitution
+    quoted: bool,
+    // of the tokens up to `lexed`
+    nesting: Nesting,
+    // the extglob option when the last line came
+    extglob: bool,
+}
+
+impl LineBuffer {
+    // the gathered commands once `line` completes them
+    pub fn push(&mut self, line: &str, extglob: bool) -> Option<Input> {
+        self.extglob = extglob;
+        self.source += line;
+        self.source.push('\n');
+        let closes_lexing = match &self.here_doc {
+            Some((delimiter, true)) => line.trim_start_matches('\t') == delimiter,
+            Some((delimiter, false)) => line == delimiter,
+            None => !self.quoted || line.contains(['\'', '"', '`', ')', '}']),
+        };
+        if !closes_lexing {
+            return None;
+        }
+        self.here_doc = None;
+        self.quoted = false;
+        let start = self.lexed_tokens;
+        self.tokens.truncate(start);
+        match tokenize(&self.source[self.lexed..], self.extglob) {
+            Ok(tokens) => self.tokens.extend(tokens),
+            Err(ParseError::HereDoc { delimiter, strip }) => {
+                self.here_doc = Some((delimiter, strip));
+                return None;
+            }
+            Err(ParseError::Incomplete(_)) => {
+                self.quoted = true;
+                return None;
+            }
+            Err(err) => return Some(self.finish(Err(err))),
+        }
+        // a line joined to the next one with a backslash waits for it, and is
+        // tokenized again with it
+        if self.tokens.last() != Some(&Token::Newline) {
+            return None;
+        }
+        for token in &self.tokens[start..] {
+            self.nesting.scan(token);
+        }
+        self.lexed = self.source.len();
+        self.lexed_tokens = self.tokens.len();
+        if self.nesting.depth > 0 {
+            return None;
+        }
+        match parse_tokens(&self.tokens) {
+            Err(ParseError::Incomplete(_)) => None,
+            list => Some(self.finish(list)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.source.is_empty()
+    }
+
+    // what was gathered so far, when the input ends
+    pub fn take(&mut self) -> Input {
+        let list = tokenize(&self.source[self.lexed..], self.extglob).and_then(|_| parse_tokens(&self.tokens));
+        self.finish(list)
+    }
+
+    // drops an unfinished command
+    pub fn clear(&mut self) {
+        *self = LineBuffer::default();
+    }
+
+    fn finish(&mut self, list: Result<List, ParseError>) -> Input {
+        let source = std::mem::take(&mut self.source);
+        self.clear();
+        Input { source, list }
+    }
+}
+
+fn unexpected(token: Option<&Token>) -> ParseError {
+    match token {
+        Some(token) => ParseError::Syntax(format!("syntax error near unexpected token `{}'", token.describe())),
+        None => ParseError::Incomplete("syntax error: unexpected end of file".to_string()),
+    }
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek() == Some(&Token::Newline) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek_reserved(&self, name: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word.is_reserved(name))
+    }
+
+    fn expect_reserved(&mut self, name: &str) -> Result<(), ParseError> {
+        if !self.peek_reserved(name) {
+            return Err(unexpected(self.peek()));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    // a list ends at the end of input or, inside a compound command, right
+    // before one of `terminators`: reserved words or ;; of case items
+    fn list(&mut self, terminators: &[&str]) -> Result<List, ParseError> {
+        let mut list = List::default();
+        loop {
+            self.skip_newlines();
+            let at_end = match self.peek() {
+                None if terminators.is_empty() => return Ok(list),
+                None => return Err(unexpected(None)),
+                Some(Token::Word(word)) => terminators.iter().any(|name| word.is_reserved(name)),
+                Some(Token::DSemi) => terminators.contains(&";;"),
+                Some(Token::RParen) => terminators.contains(&")"),
+                _ => false,
+            };
+            if at_end {
+                return Ok(list);
+            }
+            let and_or = self.and_or()?;
+            let background = match self.peek() {
+                None => false,
+                Some(Token::Semi | Token::Newline) => {
+                    self.pos += 1;
+                    false
+                }
+                Some(Token::Amp) => {
+                    self.pos += 1;
+                    true
+                }
+                Some(Token::DSemi) if terminators.contains(&";;") => false,
+                Some(Token::RParen) if terminators.contains(&")") => false,
+                token => return Err(unexpected(token)),
+            };
+            list.items.push(ListItem { and_or, background });
+        }
+    }
+
+    // the lists of compound commands can't be empty
+    fn compound_list(&mut self, terminators: &[&str]) -> Result<List, ParseError> {
+        let list = self.list(terminators)?;
+        if list.items.is_empty() {
+            return Err(unexpected(self.peek()));
+        }
+        Ok(list)
+    }
+
+    fn and_or(&mut self) -> Result<AndOr, ParseError> {
+        let first = self.pipe_sequence()?;
+        let mut rest = Vec::new();
+        loop {
+            let op = match self.peek() {
+                Some(Token::AndIf) => AndOrOp::And,
+                Some(Token::OrIf) => AndOrOp::Or,
+                _ => return Ok(AndOr { first, rest }),
+            };
+            self.pos += 1;
+            self.skip_newlines();
+            rest.push((op, self.pipe_sequence()?));
+        }
+    }
+
+    fn pipe_sequence(&mut self) -> Result<PipeSequence, ParseError> {
+        let mut commands = vec![self.command()?];
+        while self.peek() == Some(&Token::Pipe) {
+            self.pos += 1;
+            // a pipe at the end of a line continues on the next one
+            self.skip_newlines();
+            commands.push(self.command()?);
+        }
+        Ok(PipeSequence { commands })
+    }
+
+    fn command(&mut self) -> Result<Command, ParseError> {
+        let compound = if self.peek_reserved("if") {
+            CompoundCommand::If(self.if_clause()?)
+        } else if self.peek_reserved("while") || self.peek_reserved("until") {
+            CompoundCommand::Loop(self.loop_clause()?)
+        } else if self.peek_reserved("for") {
+            CompoundCommand::For(self.for_clause()?)
+        } else if self.peek_reserved("case") {
+            CompoundCommand::Case(self.case_clause()?)
+        } else if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            let body = self.compound_list(&[")"])?;
+            match self.next() {
+                Some(Token::RParen) => {}
+                token => return Err(unexpected(token.as_ref())),
+            }
+            CompoundCommand::Subshell(body)
+        } else if self.peek_reserved("{") {
+            self.pos += 1;
+            let body = self.compound_list(&["}"])?;
+            self.expect_reserved("}")?;
+            CompoundCommand::Group(body)
+        } else if let Some(Token::Arith(parts)) = self.peek() {
+            let word = Word { parts: parts.clone() };
+            self.pos += 1;
+            CompoundCommand::Arith(word)
+        } else if self.peek_reserved("function") || self.at_function_name() {
+            return Ok(Command::Function(Rc::new(self.function_def()?)));
+        } else if CLOSING_WORDS.iter().any(|name| self.peek_reserved(name)) {
+            return Err(unexpected(self.peek()));
+        } else {
+            return Ok(Command::Simple(self.simple_command()?));
+        };
+        Ok(Command::Compound(compound, self.redirects()?))
+    }
+
+    fn redirects(&mut self) -> Result<Vec<Redirect>, ParseError> {
+        let mut redirects = Vec::new();
+        while let Some(&Token::Redirect(fd, op)) = self.peek() {
+            self.pos += 1;
+            redirects.push(self.redirect(fd, op)?);
+        }
+        Ok(redirects)
+    }
+
+    // the name of a variable or function
+    fn name(&mut self) -> Result<String, ParseError> {
+        match self.next() {
+            Some(Token::Word(word)) => match word.literal() {
+                Some(name) if is_name(&name) => Ok(name),
+                _ => Err(ParseError::Syntax(format!("`{}': not a valid identifier", word.source()))),
+            },
+            token => Err(unexpected(token.as_ref())),
+        }
+    }
+
+    // name ( ) starts a function definition
+    fn at_function_name(&self) -> bool {
+        let name = match self.peek() {
+            Some(Token::Word(word)) => word.literal(),
+            _ => None,
+        };
+        name.is_some_and(|name| is_name(&name))
+            && self.tokens.get(self.pos + 1) == Some(&Token::LParen)
+            && self.tokens.get(self.pos + 2) == Some(&Token::RParen)
+    }
+
+    fn function_def(&mut self) -> Result<FunctionDef, ParseError> {
+        let keyword = self.peek_reserved("function");
+        if keyword {
+            self.pos += 1;
+        }
+        let name = self.name()?;
+        // the parentheses are optional after `function`
+        if !keyword || self.peek() == Some(&Token::LParen) {
+            for expected in [Token::LParen, Token::RParen] {
+                match self.next() {
+                    Some(token) if token == expected => {}
+                    token => return Err(unexpected(token.as_ref())),
+                }
+            }
+        }
+        self.skip_newlines();
+        self.expect_reserved("{")?;
+        let body = self.compound_list(&["}"])?;
+        self.expect_reserved("}")?;
+        Ok(FunctionDef {
+            name,
+            body,
+            redirects: self.redirects()?,
+        })
+    }
+
+    fn redirect(&mut self, fd: Option<i32>, op: RedirOp) -> Result<Redirect, ParseError> {
+        match self.next() {
+            Some(Token::Word(target)) => Ok(Redirect { fd, op, target }),
+            Some(token) => Err(unexpected(Some(&token))),
+            // more lines can't complete a redirection
+            None => Err(ParseError::Syntax("syntax error near unexpected token `newline'".to_string())),
+        }
+    }
+
+    fn simple_command(&mut self) -> Result<SimpleCommand, ParseError> {
+        let mut command = SimpleCommand::default();
+        loop {
+            match self.peek() {
+                Some(Token::Word(_)) => {
+                    if let Some(Token::Word(word)) = self.next() {
+                        command.words.push(word);
+                    }
+                }
+                Some(&Token::Redirect(fd, op)) => {
+                    self.pos += 1;
+                    let redirect = self.redirect(fd, op)?;
+                    command.redirects.push(redirect);
+                }
+                token => {
+                    if command.words.is_empty() && command.redirects.is_empty() {
+                        return Err(unexpected(token));
+                    }
+                    return Ok(command);
+                }
+            }
+        }
+    }
+
+    fn if_clause(&mut self) -> Result<IfClause, ParseError> {
+        self.expect_reserved("if")?;
+        let mut branches = Vec::new();
+        let mut else_body = None;
+        loop {
+            let condition = self.compound_list(&["then"])?;
+            self.expect_reserved("then")?;
+            let body = self.compound_list(&["elif", "else", "fi"])?;
+            branches.push((condition, body));
+            if self.peek_reserved("elif") {
+                self.pos += 1;
+                continue;
+            }
+            if self.peek_reserved("else") {
+                self.pos += 1;
+                else_body = Some(self.compound_list(&["fi"])?);
+            }
+            self.expect_reserved("fi")?;
+            return Ok(IfClause { branches, else_body });
+        }
+    }
+
+    fn loop_clause(&mut self) -> Result<LoopClause, ParseError> {
+        let until = self.peek_reserved("until");
+        self.pos += 1;
+        let condition = self.compound_list(&["do"])?;
+        let body = self.do_group()?;
+        Ok(LoopClause { condition, body, until })
+    }
+
+    fn do_group(&mut self) -> Result<List, ParseError> {
+        self.expect_reserved("do")?;
+        let body = self.compound_list(&["done"])?;
+        self.expect_reserved("done")?;
+        Ok(body)
+    }
+
+    fn for_clause(&mut self) -> Result<ForClause, ParseError> {
+        self.expect_reserved("for")?;
+        let name = self.name()?;
+        self.skip_newlines();
+        let mut words = None;
+        if self.peek_reserved("in") {
+            self.pos += 1;
+            let mut list = Vec::new();
+            while let Some(Token::Word(word)) = self.peek() {
+                list.push(word.clone());
+                self.pos += 1;
+            }
+            words = Some(list);
+            match self.next() {
+                Some(Token::Semi | Token::Newline) => {}
+                token => return Err(unexpected(token.as_ref())),
+            }
+        } else if self.peek() == Some(&Token::Semi) {
+            self.pos += 1;
+        }
+        self.skip_newlines();
+        let body = self.do_group()?;
+        Ok(ForClause { name, words, body })
+    }
+
+    fn case_clause(&mut self) -> Result<CaseClause, ParseError> {
+        self.expect_reserved("case")?;
+        let word = match self.next() {
+            Some(Token::Word(word)) => word,
+            token => return Err(unexpected(token.as_ref())),
+        };
+        self.skip_newlines();
+        self.expect_reserved("in")?;
+        let mut items = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.peek_reserved("esac") {
+                self.pos += 1;
+                return Ok(CaseClause { word, items });
+            }
+            if self.peek() == Some(&Token::LParen) {
+                self.pos += 1;
+            }
+            let mut patterns = Vec::new();
+            loop {
+                match self.next() {
+                    Some(Token::Word(pattern)) => patterns.push(pattern),
+                    token => return Err(unexpected(token.as_ref())),
+                }
+                match self.next() {
+                    Some(Token::Pipe) => continue,
+                    Some(Token::RParen) => break,
+                    token => return Err(unexpected(token.as_ref())),
+                }
+            }
+            let body = self.list(&[";;", "esac"])?;
+            items.push(CaseItem { patterns, body });
+            if self.peek() == Some(&Token::DSemi) {
+                self.pos += 1;
+            } else if !self.peek_reserved("esac") {
+                return Err(unexpected(self.peek()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // pushes the lines, returns how many were gathered when the commands got complete
+    fn gather(lines: &[String]) -> Option<(usize, Input)> {
+        let mut buffer = LineBuffer::default();
+        lines.iter().enumerate().find_map(|(i, line)| buffer.push(line, false).map(|input| (i + 1, input)))
+    }
+
+    #[test]
+    fn here_document_ends_at_its_delimiter() {
+        let mut lines = vec!["cat <<EOF".to_string()];
+        lines.extend((0..20000).map(|i| format!("line {} fi ) done", i)));
+        lines.push("EOF".to_string());
+        let (count, input) = gather(&lines).unwrap();
+        assert_eq!(count, lines.len());
+        assert!(input.list.is_ok());
+    }
+
+    #[test]
+    fn backslash_joins_lines() {
+        let lines = ["mecho a \\".to_string(), "b".to_string(), "mecho c".to_string()];
+        let (count, input) = gather(&lines).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(input.list.unwrap().items.len(), 1);
+    }
+
+    #[test]
+    fn compound_command_ends_at_its_closing_word() {
+        let mut lines = vec!["f() {".to_string(), "  case $1 in".to_string(), "    if) echo \"(\"".to_string(), "      ;;".to_string()];
+        lines.extend((0..5000).map(|i| format!("    {}) if true; then echo fi; fi ;;", i)));
+        lines.extend(["  esac".to_string(), "}".to_string(), "f".to_string()]);
+        let (count, input) = gather(&lines).unwrap();
+        assert_eq!(count, lines.len() - 1);
+        assert!(input.list.is_ok());
+    }
+}
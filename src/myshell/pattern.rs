@@ -0,0 +1,515 @@
+use std::fs;
+use std::path::Path;
+
+// Shell patterns, for pathname expansion, case and ${name#pattern}. In a
+// pattern a backslash quotes the next character, quoted parts of words are
+// turned into that form by `escape`.
+
+// options of pathname expansion, set with shopt
+#[derive(Clone, Debug, Default)]
+pub struct GlobOptions {
+    // ** matches any number of directories
+    pub globstar: bool,
+    // * and ? match a leading dot
+    pub dotglob: bool,
+    // patterns without matches are removed
+    pub nullglob: bool,
+    // patterns without matches are an error
+    pub failglob: bool,
+    pub nocaseglob: bool,
+    // ?(...), *(...), +(...), @(...) and !(...)
+    pub extglob: bool,
+}
+
+pub const GLOB_OPTIONS: [&str; 6] = ["dotglob", "extglob", "failglob", "globstar", "nocaseglob", "nullglob"];
+
+impl GlobOptions {
+    pub fn new() -> GlobOptions {
+        GlobOptions::default()
+    }
+
+    pub fn option(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "dotglob" => Some(&mut self.dotglob),
+            "extglob" => Some(&mut self.extglob),
+            "failglob" => Some(&mut self.failglob),
+            "globstar" => Some(&mut self.globstar),
+            "nocaseglob" => Some(&mut self.nocaseglob),
+            "nullglob" => Some(&mut self.nullglob),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum ClassItem {
+    Char(char),
+    Range(char, char),
+    // [:alpha:] and the like
+    Named(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Char(char),
+    // ?
+    Any,
+    // *
+    Star,
+    // [...], [!...] or [^...]
+    Class { negated: bool, items: Vec<ClassItem> },
+    // the operator character and the alternatives between | of an extended pattern
+    Ext { kind: char, alternatives: Vec<Vec<Token>> },
+}
+
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    tokens: Vec<Token>,
+}
+
+// every character that means something in a pattern is quoted
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\*?[]()|!@+".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn unescape(pattern: &str) -> String {
+    let mut text = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.extend(chars.next()),
+            c => text.push(c),
+        }
+    }
+    text
+}
+
+impl Pattern {
+    // a [ without its ] or an unclosed extended pattern is taken literally
+    pub fn new(pattern: &str, extglob: bool) -> Pattern {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut pos = 0;
+        Pattern {
+            tokens: parse(&chars, &mut pos, extglob, false),
+        }
+    }
+
+    // no characters that match more than themselves
+    pub fn is_literal(&self) -> bool {
+        self.tokens.iter().all(|token| matches!(token, Token::Char(_)))
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        match_here(&self.tokens, &text, false)
+    }
+
+    // a file name in a directory, its leading dot has to be matched literally
+    fn matches_name(&self, name: &str, options: &GlobOptions) -> bool {
+        if name.starts_with('.') && !options.dotglob && self.tokens.first() != Some(&Token::Char('.')) {
+            return false;
+        }
+        let name: Vec<char> = name.chars().collect();
+        match_here(&self.tokens, &name, options.nocaseglob)
+    }
+}
+
+fn parse(chars: &[char], pos: &mut usize, extglob: bool, nested: bool) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    while let Some(&c) = chars.get(*pos) {
+        if nested && (c == '|' || c == ')') {
+            break;
+        }
+        *pos += 1;
+        match c {
+            '\\' => match chars.get(*pos) {
+                Some(&next) => {
+                    tokens.push(Token::Char(next));
+                    *pos += 1;
+                }
+                None => tokens.push(Token::Char('\\')),
+            },
+            '?' | '*' | '+' | '@' | '!' if extglob && chars.get(*pos) == Some(&'(') && ext_closes(chars, *pos) => {
+                *pos += 1;
+                let mut alternatives = Vec::new();
+                loop {
+                    alternatives.push(parse(chars, pos, extglob, true));
+                    let separator = chars.get(*pos).copied();
+                    *pos += 1;
+                    if separator != Some('|') {
+                        break;
+                    }
+                }
+                tokens.push(Token::Ext { kind: c, alternatives });
+            }
+            '?' => tokens.push(Token::Any),
+            // ** is the same as * within a name
+            '*' if tokens.last() == Some(&Token::Star) => {}
+            '*' => tokens.push(Token::Star),
+            '[' => match parse_class(chars, *pos) {
+                Some((class, end)) => {
+                    tokens.push(class);
+                    *pos = end;
+                }
+                None => tokens.push(Token::Char('[')),
+            },
+            c => tokens.push(Token::Char(c)),
+        }
+    }
+    tokens
+}
+
+// whether the ( at `open` has its )
+fn ext_closes(chars: &[char], open: usize) -> bool {
+    let mut depth = 0;
+    let mut i = open;
+    while let Some(&c) = chars.get(i) {
+        match c {
+            '\\' => i += 1,
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return true;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    false
+}
+
+// after the [, the class and the position after its ]
+fn parse_class(chars: &[char], start: usize) -> Option<(Token, usize)> {
+    let mut pos = start;
+    let negated = matches!(chars.get(pos), Some('!' | '^'));
+    if negated {
+        pos += 1;
+    }
+    let mut items = Vec::new();
+    // a ] right at the start is part of the class
+    let mut first = true;
+    loop {
+        let c = *chars.get(pos)?;
+        pos += 1;
+        match c {
+            ']' if !first => return Some((Token::Class { negated, items }, pos)),
+            '[' if chars.get(pos) == Some(&':') => {
+                let rest: String = chars[pos + 1..].iter().collect();
+                match rest.find(":]") {
+                    Some(end) => {
+                        items.push(ClassItem::Named(rest[..end].to_string()));
+                        pos += 1 + rest[..end].chars().count() + 2;
+                    }
+                    None => items.push(ClassItem::Char('[')),
+                }
+            }
+            c => {
+                let c = if c == '\\' {
+                    let escaped = *chars.get(pos)?;
+                    pos += 1;
+                    escaped
+                } else {
+                    c
+                };
+                if chars.get(pos) == Some(&'-') && chars.get(pos + 1).is_some_and(|&end| end != ']') {
+                    let mut end = chars[pos + 1];
+                    pos += 2;
+                    if end == '\\' {
+                        end = *chars.get(pos)?;
+                        pos += 1;
+                    }
+                    items.push(ClassItem::Range(c, end));
+                } else {
+                    items.push(ClassItem::Char(c));
+                }
+            }
+        }
+        first = false;
+    }
+}
+
+fn same(a: char, b: char, nocase: bool) -> bool {
+    a == b || (nocase && a.to_lowercase().eq(b.to_lowercase()))
+}
+
+fn in_class(items: &[ClassItem], c: char, nocase: bool) -> bool {
+    let variants = if nocase {
+        c.to_lowercase().chain(c.to_uppercase()).chain(Some(c)).collect()
+    } else {
+        vec![c]
+    };
+    variants.into_iter().any(|c| {
+        items.iter().any(|item| match item {
+            ClassItem::Char(d) => *d == c,
+            ClassItem::Range(from, to) => *from <= c && c <= *to,
+            ClassItem::Named(name) => match name.as_str() {
+                "alnum" => c.is_alphanumeric(),
+                "alpha" => c.is_alphabetic(),
+                "blank" => c == ' ' || c == '\t',
+                "cntrl" => c.is_control(),
+                "digit" => c.is_ascii_digit(),
+                "graph" => c.is_ascii_graphic(),
+                "lower" => c.is_lowercase(),
+                "print" => c.is_ascii_graphic() || c == ' ',
+                "punct" => c.is_ascii_punctuation(),
+                "space" => c.is_whitespace(),
+                "upper" => c.is_uppercase(),
+                "xdigit" => c.is_ascii_hexdigit(),
+                _ => false,
+            },
+        })
+    })
+}
+
+// whether `tokens` match all of `text`, trying the possible lengths of
+// extended patterns, and of * before them, in turn
+fn match_here(tokens: &[Token], text: &[char], nocase: bool) -> bool {
+    if !tokens.iter().any(|token| matches!(token, Token::Ext { .. })) {
+        return match_simple(tokens, text, nocase);
+    }
+    let (token, rest) = match tokens.split_first() {
+        Some(split) => split,
+        None => return text.is_empty(),
+    };
+    match token {
+        Token::Star => rest.is_empty() || (0..=text.len()).any(|i| match_here(rest, &text[i..], nocase)),
+        Token::Ext { kind, alternatives } => {
+            let one = |end: usize| alternatives.iter().any(|alt| match_here(alt, &text[..end], nocase));
+            match kind {
+                '?' => match_here(rest, text, nocase) || (0..=text.len()).any(|end| one(end) && match_here(rest, &text[end..], nocase)),
+                '@' => (0..=text.len()).any(|end| one(end) && match_here(rest, &text[end..], nocase)),
+                '*' => match_repeated(alternatives, rest, text, nocase),
+                '+' => (1..=text.len()).any(|end| one(end) && match_repeated(alternatives, rest, &text[end..], nocase)),
+                // !(...)
+                _ => (0..=text.len()).any(|end| !one(end) && match_here(rest, &text[end..], nocase)),
+            }
+        }
+        token => !text.is_empty() && match_one(token, text[0], nocase) && match_here(rest, &text[1..], nocase),
+    }
+}
+
+fn match_one(token: &Token, c: char, nocase: bool) -> bool {
+    match token {
+        Token::Char(expected) => same(*expected, c, nocase),
+        Token::Any => true,
+        Token::Class { negated, items } => in_class(items, c, nocase) != *negated,
+        Token::Star | Token::Ext { .. } => false,
+    }
+}
+
+// Without extended patterns a * only has to be tried again from the last
+// one: whatever an earlier * would take, the later one can take as well.
+fn match_simple(tokens: &[Token], text: &[char], nocase: bool) -> bool {
+    let (mut t, mut i) = (0, 0);
+    // the token after the last * and where the text after it starts
+    let mut resume = None;
+    while i < text.len() {
+        match tokens.get(t) {
+            Some(Token::Star) => {
+                t += 1;
+                resume = Some((t, i));
+                continue;
+            }
+            Some(token) if match_one(token, text[i], nocase) => {
+                t += 1;
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match resume {
+            Some((after, start)) => {
+                t = after;
+                i = start + 1;
+                resume = Some((after, i));
+            }
+            None => return false,
+        }
+    }
+    tokens[t..].iter().all(|token| *token == Token::Star)
+}
+
+// any number of the alternatives, then the rest
+fn match_repeated(alternatives: &[Vec<Token>], rest: &[Token], text: &[char], nocase: bool) -> bool {
+    match_here(rest, text, nocase)
+        || (1..=text.len()).any(|end| {
+            alternatives.iter().any(|alt| match_here(alt, &text[..end], nocase))
+                && match_repeated(alternatives, rest, &text[end..], nocase)
+        })
+}
+
+// The paths matching `pattern`, sorted by their bytes so the order does not
+// depend on the locale. A pattern ending with / only matches directories.
+pub fn expand_pathname(pattern: &str, options: &GlobOptions) -> Vec<String> {
+    let components: Vec<&str> = pattern.split('/').collect();
+    let mut paths = vec![String::new()];
+    for (i, component) in components.iter().enumerate() {
+        let last = i + 1 == components.len();
+        if component.is_empty() {
+            if i == 0 {
+                paths = vec!["/".to_string()];
+            } else if last {
+                paths.retain(|path| Path::new(path).is_dir());
+                paths.iter_mut().for_each(|path| path.push('/'));
+            }
+            continue;
+        }
+        if *component == "**" && options.globstar {
+            let mut found = Vec::new();
+            for path in &paths {
+                // in the middle ** can match no directory at all
+                if !last {
+                    found.push(path.clone());
+                }
+                walk(path, options, last, &mut found);
+            }
+            paths = found;
+            continue;
+        }
+        let matcher = Pattern::new(component, options.extglob);
+        let mut found = Vec::new();
+        for path in &paths {
+            if matcher.is_literal() {
+                let joined = join(path, &unescape(component));
+                // dangling symbolic links match as well
+                if fs::symlink_metadata(&joined).is_ok() {
+                    found.push(joined);
+                }
+                continue;
+            }
+            let entries = match fs::read_dir(if path.is_empty() { "." } else { path }) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if matcher.matches_name(&name, options) {
+                    found.push(join(path, &name));
+                }
+            }
+        }
+        if !last {
+            found.retain(|path| Path::new(path).is_dir());
+        }
+        paths = found;
+    }
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+fn join(directory: &str, name: &str) -> String {
+    if directory.is_empty() {
+        name.to_string()
+    } else if directory.ends_with('/') {
+        format!("{}{}", directory, name)
+    } else {
+        format!("{}/{}", directory, name)
+    }
+}
+
+// everything below `directory` for a trailing **, otherwise its subdirectories;
+// symbolic links to directories are not followed
+fn walk(directory: &str, options: &GlobOptions, files: bool, found: &mut Vec<String>) {
+    let entries = match fs::read_dir(if directory.is_empty() { "." } else { directory }) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') && !options.dotglob {
+            continue;
+        }
+        let path = join(directory, &name);
+        let is_dir = entry.file_type().map(|kind| kind.is_dir()).unwrap_or(false);
+        if is_dir || files {
+            found.push(path.clone());
+        }
+        if is_dir {
+            walk(&path, options, files, found);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Pattern::new(pattern, true).matches(text)
+    }
+
+    #[test]
+    fn star_and_any() {
+        assert!(matches("*", ""));
+        assert!(matches("a*c", "abbbc"));
+        assert!(matches("*bc", "abcbc"));
+        assert!(matches("a**b**", "ab"));
+        assert!(matches("a?c", "abc"));
+        assert!(!matches("a?c", "ac"));
+        assert!(!matches("*c*", "ab"));
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+    }
+
+    #[test]
+    fn star_does_not_backtrack_exponentially() {
+        let started = Instant::now();
+        let text = "a".repeat(60) + "b";
+        assert!(!matches("*a*a*a*a*a*a*a*a*a*a*c", &text));
+        assert!(matches("*a*a*a*a*a*a*a*a*a*a*b", &text));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("[abc]", "b"));
+        assert!(!matches("[abc]", "d"));
+        assert!(matches("[a-c]x", "bx"));
+        assert!(matches("[!a-c]", "d"));
+        assert!(matches("[^a-c]", "d"));
+        assert!(!matches("[!a-c]", "a"));
+        assert!(matches("[[:digit:]][[:alpha:]]", "1z"));
+        assert!(matches("[]a]", "]"));
+        // without its ] the [ is an ordinary character
+        assert!(matches("[ab", "[ab"));
+    }
+
+    #[test]
+    fn extended_patterns() {
+        assert!(matches("?(a|b)c", "c"));
+        assert!(matches("?(a|b)c", "ac"));
+        assert!(!matches("?(a|b)c", "abc"));
+        assert!(matches("*(ab)c", "ababc"));
+        assert!(matches("+(ab)c", "abc"));
+        assert!(!matches("+(ab)c", "c"));
+        assert!(matches("@(foo|bar)", "bar"));
+        assert!(!matches("@(foo|bar)", "foobar"));
+        assert!(matches("!(foo)", "bar"));
+        assert!(!matches("!(foo)", "foo"));
+        assert!(matches("*.!(txt)", "a.rs"));
+        assert!(!matches("*.!(txt)", "a.txt"));
+        // without extglob they are ordinary characters
+        assert!(Pattern::new("@(a)", false).matches("@(a)"));
+        assert!(!Pattern::new("@(a)", false).matches("a"));
+    }
+
+    #[test]
+    fn names_with_a_leading_dot() {
+        let options = GlobOptions::new();
+        assert!(!Pattern::new("*", false).matches_name(".hidden", &options));
+        assert!(Pattern::new(".*", false).matches_name(".hidden", &options));
+        let options = GlobOptions { dotglob: true, ..GlobOptions::new() };
+        assert!(Pattern::new("*", false).matches_name(".hidden", &options));
+    }
+}
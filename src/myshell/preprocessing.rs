@@ -1,275 +1,769 @@
-use std::collections::HashMap;
 use std::env;
 
-use super::{CommandType, MyShell, Pipeline, REDIRECTIONS, REDIRECTION_KEYS, SPECIAL_SYMBOLS};
-use crate::string_utils::{find_all_start_end_symb, find_all_subshells};
+use super::lexer::{is_name, ParamExp, ParamOp, RedirOp, Word, WordPart};
+use super::parser::{Command, PipeSequence, Redirect};
+use super::braces::expand_braces;
+use super::descriptors::{close_private, inherited_fd, Descriptors, CLOSED, MAX_FD};
+use super::pattern::{escape, expand_pathname, Pattern};
+use super::utils::wait_for;
+use super::{CommandType, MyShell, Pipeline};
 
-use glob::glob;
-use nix::libc::{close, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
-use nix::unistd::pipe;
-use std::fs::File;
-use std::os::unix::io::IntoRawFd;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::libc::{STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
+use nix::unistd::{self, dup2, fork, getuid, pipe2, ForkResult, User};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::process;
 
-impl MyShell {
-    pub fn preprocess_comments(line: &str) -> &str {
-        match line.find('#') {
-            Some(idx) => &line[0..idx],
-            None => line,
-        }
-    }
-    pub fn split_command(line: &str) -> Result<Vec<String>, String> {
-        let one_quote = find_all_start_end_symb(line, "'")?;
-        let two_quote = find_all_start_end_symb(line, "\"")?;
-        let subshells = find_all_subshells(line)?;
-
-        let mut non_split_regions = one_quote;
-        non_split_regions.extend(two_quote);
-        non_split_regions.extend(subshells);
-
-        let mut splitted: Vec<String> = Vec::new();
-        let mut next: usize = 0;
-        let mut prev: usize = 0;
-        for ci in 0..line.len() {
-            let c = line.as_bytes()[ci] as char;
-            let mut can_split = true;
-            for (s, e) in &non_split_regions {
-                if (*s <= ci) && (ci <= *e) {
-                    can_split = false;
+const DEFAULT_IFS: &str = " \t\n";
+
+// a field of an expanded word, and the same as a pattern for pathname expansion
+pub struct ExpandedWord {
+    pub text: String,
+    pub pattern: String,
+}
+
+// Collects the fields a word expands to. Unquoted results of expansions are
+// split on IFS characters, everything else only extends the current field.
+struct Fields {
+    done: Vec<String>,
+    current: String,
+    // the fields as patterns for pathname expansion, with quoted text escaped
+    patterns: Vec<String>,
+    pattern: String,
+    // quotes make a field even when it stays empty
+    started: bool,
+    // IFS whitespace just ended a field, a following delimiter belongs to it
+    after_space: bool,
+    // None when the word is not split at all
+    ifs: Option<String>,
+}
+
+impl Fields {
+    fn new(ifs: Option<String>) -> Fields {
+        Fields {
+            done: Vec::new(),
+            current: String::new(),
+            patterns: Vec::new(),
+            pattern: String::new(),
+            started: false,
+            after_space: false,
+            ifs,
+        }
+    }
+
+    fn push_text(&mut self, text: &str, quoted: bool) {
+        self.current += text;
+        if quoted {
+            self.pattern += &escape(text);
+        } else {
+            self.pattern += text;
+        }
+        self.started = true;
+        self.after_space = false;
+    }
+
+    fn push_expansion(&mut self, value: &str, quoted: bool) {
+        let ifs = match &self.ifs {
+            Some(ifs) if !quoted && !ifs.is_empty() => ifs.clone(),
+            _ => {
+                if !value.is_empty() {
+                    self.push_text(value, quoted);
                 }
+                return;
             }
-            if can_split && c == ' ' {
-                if prev != next {
-                    splitted.push(line[prev..next].trim().to_string());
+        };
+        for c in value.chars() {
+            if !ifs.contains(c) {
+                self.current.push(c);
+                self.pattern.push(c);
+                self.started = true;
+                self.after_space = false;
+            } else if c.is_whitespace() {
+                if self.started {
+                    self.end_field();
+                    self.after_space = true;
                 }
-                next += 1;
-                prev = next;
-                continue;
+            } else {
+                if !self.after_space {
+                    self.end_field();
+                }
+                self.after_space = false;
             }
-            next += 1;
-        }
-        splitted.push(line[prev..].trim().to_string());
-        Ok(splitted)
-    }
-    pub fn preprocess_pipeline(commands: Vec<String>) -> Result<Pipeline, String> {
-        let n_steps = commands.iter().filter(|&command| *command == "|").count() + 1;
-        if n_steps == 1 {
-            let mut subshell_comm: Vec<HashMap<usize, Vec<(usize, usize)>>> = Vec::new();
-            subshell_comm.push(HashMap::new());
-            subshell_comm[0].insert(1, Vec::new());
-            return Ok(Pipeline {
-                steps: Vec::from(vec![commands]),
-                ioe_descriptors: vec![[0, 1, 2]],
-                types: Vec::from([CommandType::External]),
-                subshell_comm,
+        }
+    }
+
+    // between the parameters of $@
+    fn end_param(&mut self) {
+        if self.started {
+            self.end_field();
+        }
+        self.after_space = false;
+    }
+
+    fn end_field(&mut self) {
+        self.done.push(std::mem::take(&mut self.current));
+        self.patterns.push(std::mem::take(&mut self.pattern));
+        self.started = false;
+    }
+
+    fn finish(self) -> Vec<String> {
+        self.finish_words().into_iter().map(|word| word.text).collect()
+    }
+
+    fn finish_words(mut self) -> Vec<ExpandedWord> {
+        if self.started {
+            self.end_field();
+        }
+        self.done
+            .into_iter()
+            .zip(self.patterns)
+            .map(|(text, pattern)| ExpandedWord { text, pattern })
+            .collect()
+    }
+}
+
+impl MyShell {
+    // connects the commands of the sequence with pipes, the commands
+    // themselves are filled in once their words are expanded
+    pub fn preprocess_pipeline(sequence: &PipeSequence) -> Result<Pipeline, String> {
+        let n_steps = sequence.commands.len();
+        let mut descriptors: Vec<Descriptors> = (0..n_steps).map(|_| Descriptors::new()).collect();
+        for i in 1..n_steps {
+            // close-on-exec, so other commands of the pipeline don't hold the pipe open
+            let connected = pipe2(OFlag::O_CLOEXEC).map_err(|err| err.to_string()).and_then(|(read_end, write_end)| {
+                descriptors[i - 1].set(STDOUT_FILENO, write_end)?;
+                descriptors[i].set(STDIN_FILENO, read_end)
             });
+            if let Err(err) = connected {
+                descriptors.iter_mut().for_each(Descriptors::close_all);
+                return Err(err);
+            }
         }
-        let mut steps: Vec<Vec<String>> = Vec::new();
-        steps.reserve(n_steps);
+        Ok(Pipeline {
+            steps: vec![Vec::new(); n_steps],
+            descriptors,
+            types: sequence
+                .commands
+                .iter()
+                .map(|command| match command {
+                    Command::Simple(_) => CommandType::External,
+                    Command::Compound(..) | Command::Function(_) => CommandType::Compound,
+                })
+                .collect(),
+            background: false,
+        })
+    }
 
-        let mut ioe_descriptors: Vec<[i32; 3]> = Vec::new();
-        ioe_descriptors.reserve(n_steps);
+    // applies redirections of every command in order, so `> file 2>&1`
+    // sends both streams to the file and `2>&1 > file` only stdout
+    pub fn preprocess_redirections(&mut self, mut p: Pipeline, sequence: &PipeSequence) -> Result<Pipeline, String> {
+        for (step_i, command) in sequence.commands.iter().enumerate() {
+            for redirect in command.redirects() {
+                if let Err(err) = self.apply_redirect(&mut p.descriptors[step_i], redirect) {
+                    p.descriptors.iter_mut().for_each(Descriptors::close_all);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(p)
+    }
 
-        let types: Vec<CommandType> = vec![CommandType::External; n_steps];
+    // the descriptors with `redirects` applied, for commands outside a pipeline
+    pub fn redirect_descriptors(&mut self, redirects: &[Redirect]) -> Result<Descriptors, String> {
+        let mut descs = Descriptors::new();
+        for redirect in redirects {
+            if let Err(err) = self.apply_redirect(&mut descs, redirect) {
+                descs.close_all();
+                return Err(err);
+            }
+        }
+        Ok(descs)
+    }
 
-        let mut subshell_comm: Vec<HashMap<usize, Vec<(usize, usize)>>> = Vec::new();
-        subshell_comm.reserve(n_steps);
+    fn apply_redirect(&mut self, descs: &mut Descriptors, redirect: &Redirect) -> Result<(), String> {
+        let default_fd = match redirect.op {
+            RedirOp::In | RedirOp::DupIn | RedirOp::ReadWrite | RedirOp::HereDoc(_) | RedirOp::HereString => {
+                STDIN_FILENO
+            }
+            _ => STDOUT_FILENO,
+        };
+        let fd = redirect.fd.unwrap_or(default_fd);
+        if !(0..=MAX_FD).contains(&fd) {
+            return Err(format!("{}: bad file descriptor, only 0 to {} can be redirected", fd, MAX_FD));
+        }
+        if let RedirOp::HereDoc(_) | RedirOp::HereString = redirect.op {
+            // the text is neither split nor globbed
+            let mut text = self.expand_word(&redirect.target, false)?.concat();
+            if redirect.op == RedirOp::HereString {
+                text.push('\n');
+            }
+            return descs.set(fd, here_document(&text)?);
+        }
+        let target = match self.expand_word(&redirect.target, true)?.as_slice() {
+            [target] => target.clone(),
+            _ => return Err(format!("{}: ambiguous redirect", redirect.target.source())),
+        };
+        let duplicate = matches!(redirect.op, RedirOp::DupOut | RedirOp::DupIn);
+        if duplicate && target == "-" {
+            return descs.set(fd, CLOSED);
+        }
+        if duplicate && !target.is_empty() && target.chars().all(|c| c.is_ascii_digit()) {
+            let source = target.parse().map_err(|_| format!("{}: bad file descriptor", target))?;
+            // every number owns its descriptor, so the copy gets one of its own
+            let desc = descs.duplicate(source)?;
+            return descs.set(fd, desc);
+        }
+        let mut options = OpenOptions::new();
+        match redirect.op {
+            RedirOp::In => options.read(true),
+            RedirOp::ReadWrite => options.read(true).write(true).create(true),
+            RedirOp::Append | RedirOp::AppendErr => options.append(true).create(true),
+            // >&file without a number is &>file
+            RedirOp::DupOut if redirect.fd.is_none() => options.write(true).create(true).truncate(true),
+            RedirOp::DupOut | RedirOp::DupIn => return Err(format!("{}: ambiguous redirect", target)),
+            _ => options.write(true).create(true).truncate(true),
+        };
+        let desc = options.open(&target).map_err(|err| format!("{}: {}", target, err))?.into_raw_fd();
+        if matches!(redirect.op, RedirOp::OutErr | RedirOp::AppendErr | RedirOp::DupOut) {
+            // &> file, &>> file and >& file
+            let err_desc = fcntl(desc, FcntlArg::F_DUPFD_CLOEXEC(3)).map_err(|err| err.to_string());
+            descs.set(STDOUT_FILENO, desc)?;
+            descs.set(STDERR_FILENO, err_desc?)
+        } else {
+            descs.set(fd, desc)
+        }
+    }
 
-        steps.push(Vec::new());
-        for command in &commands {
-            if *command == "|" {
-                steps.push(Vec::new());
+    // a lone name=value is an assignment, its value is neither brace expanded
+    // nor split into fields
+    pub fn expand_words(&mut self, words: &[Word]) -> Result<Vec<ExpandedWord>, String> {
+        let split = !(words.len() == 1 && words[0].is_assignment());
+        let mut result = Vec::new();
+        for word in words {
+            if !split {
+                result.extend(self.expand_fields(word, split)?);
                 continue;
             }
-            let last_step_len = steps.len();
-            steps[last_step_len - 1].push((*command).clone());
+            for word in expand_braces(word) {
+                result.extend(self.expand_fields(&word, split)?);
+            }
         }
+        Ok(result)
+    }
 
-        let mut pfds: (i32, i32) = (0, 1);
-        let mut pfds_prev: (i32, i32) = (0, 1);
-        for i in 0..n_steps {
-            if i != n_steps - 1 {
-                pfds = match pipe() {
-                    Ok(fds) => fds,
-                    Err(err) => return Err(err.to_string()),
-                };
+    pub fn expand_word(&mut self, word: &Word, split: bool) -> Result<Vec<String>, String> {
+        Ok(self.expand_fields(word, split)?.into_iter().map(|word| word.text).collect())
+    }
+
+    fn expand_fields(&mut self, word: &Word, split: bool) -> Result<Vec<ExpandedWord>, String> {
+        let mut fields = Fields::new(if split { Some(self.ifs()) } else { None });
+        let parts = self.expand_tildes(word);
+        self.expand_parts(&parts, false, &mut fields)?;
+        Ok(fields.finish_words())
+    }
+
+    // ~ at the start of a word, and after the = and every : of an assignment
+    fn expand_tildes(&self, word: &Word) -> Vec<WordPart> {
+        let first = match word.parts.first() {
+            Some(WordPart::Lit(first)) if first.contains('~') => first,
+            _ => return word.parts.clone(),
+        };
+        // a tilde prefix ends at a slash, or at the end of the word
+        let alone = word.parts.len() == 1;
+        let mut parts = Vec::new();
+        let pieces: Vec<&str> = match (word.is_assignment(), first.split_once('=')) {
+            (true, Some((name, value))) => {
+                parts.push(WordPart::Lit(format!("{}=", name)));
+                value.split(':').collect()
             }
-            if i == 0 {
-                ioe_descriptors.push([STDIN_FILENO, pfds.1, STDERR_FILENO]);
-            } else if i == n_steps - 1 {
-                ioe_descriptors.push([pfds_prev.0, STDOUT_FILENO, STDERR_FILENO]);
-            } else {
-                ioe_descriptors.push([pfds_prev.0, pfds.1, STDERR_FILENO]);
+            _ => vec![first.as_str()],
+        };
+        for (i, piece) in pieces.iter().enumerate() {
+            if i > 0 {
+                parts.push(WordPart::Lit(":".to_string()));
+            }
+            let at_end = i + 1 < pieces.len() || alone;
+            match self.tilde(piece, at_end) {
+                Some((home, rest)) => {
+                    // the directory is not split or globbed
+                    parts.push(WordPart::Quoted(home));
+                    parts.push(WordPart::Lit(rest.to_string()));
+                }
+                None => parts.push(WordPart::Lit(piece.to_string())),
             }
-            pfds_prev = pfds;
         }
+        parts.extend(word.parts[1..].iter().cloned());
+        parts
+    }
 
-        Ok(Pipeline {
-            steps,
-            ioe_descriptors,
-            types,
-            subshell_comm,
-        })
+    // the directory for ~, ~user, ~+ or ~- at the start of `text` and the rest of it
+    fn tilde<'a>(&self, text: &'a str, at_end: bool) -> Option<(String, &'a str)> {
+        let prefix = text.strip_prefix('~')?;
+        let (name, rest) = match prefix.find('/') {
+            Some(slash) => prefix.split_at(slash),
+            None if at_end => (prefix, ""),
+            None => return None,
+        };
+        let directory = match name {
+            "" => match self.variable("HOME") {
+                home if !home.is_empty() => home,
+                _ => User::from_uid(getuid()).ok()??.dir.to_string_lossy().into_owned(),
+            },
+            "+" => env::current_dir().ok()?.to_string_lossy().into_owned(),
+            "-" => Some(self.variable("OLDPWD")).filter(|dir| !dir.is_empty())?,
+            user => User::from_name(user).ok()??.dir.to_string_lossy().into_owned(),
+        };
+        Some((directory, rest))
     }
-    pub fn preprocess_subshells(mut p: Pipeline) -> Result<Pipeline, String> {
-        let n_steps = p.steps.len();
-        for step_i in 0..n_steps {
-            let command = &p.steps[step_i];
-            // let subshells: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
-            for i in 0..command.len() {
-                let subshells = find_all_subshells(&command[i])?;
-                if !subshells.is_empty() {
-                    p.subshell_comm[step_i].insert(i, subshells);
+
+    fn expand_parts(&mut self, parts: &[WordPart], quoted: bool, fields: &mut Fields) -> Result<(), String> {
+        for part in parts {
+            match part {
+                WordPart::Lit(s) => fields.push_text(s, quoted),
+                WordPart::Quoted(s) => fields.push_text(s, true),
+                WordPart::DQuoted(inner) => {
+                    // "" is an empty field rather than none, but "$@" without parameters
+                    // and "${a[@]}" without elements are none
+                    let empty_list = inner.iter().any(|part| match part {
+                        WordPart::Var(name) => name == "@" && self.positional.is_empty(),
+                        WordPart::Param(exp) => {
+                            exp.op == ParamOp::Value && list_subscript(exp) == Some(false) && self.elements(&exp.name).is_empty()
+                        }
+                        _ => false,
+                    });
+                    if !empty_list {
+                        fields.push_text("", true);
+                    }
+                    self.expand_parts(inner, true, fields)?;
+                }
+                // every parameter is a field of its own, "$@" even when quoted
+                WordPart::Var(name) if name == "@" || (name == "*" && !quoted) => {
+                    let params = self.positional.clone();
+                    MyShell::push_params(&params, quoted, fields);
+                }
+                WordPart::Var(name) => {
+                    let value = self.variable(name);
+                    fields.push_expansion(&value, quoted);
                 }
+                WordPart::CmdSubst(source) => {
+                    let output = self.command_output(source)?;
+                    fields.push_expansion(&output, quoted);
+                }
+                WordPart::Param(exp) => self.expand_param(exp, quoted, fields)?,
+                WordPart::Arith(parts) => {
+                    let value = self.expand_arith(parts)?;
+                    fields.push_expansion(&value.to_string(), quoted);
+                }
+                WordPart::ProcSubst { output, source } => {
+                    let path = self.process_substitution(source, *output)?;
+                    fields.push_text(&path, true);
+                }
+                WordPart::Array(_) => return Err("syntax error near unexpected token `('".to_string()),
             }
         }
-        Ok(p)
+        Ok(())
     }
-    pub fn preprocess_redirections(mut p: Pipeline) -> Result<Pipeline, String> {
-        for step_i in 0..p.steps.len() {
-            let command = &mut p.steps[step_i];
-            // for &redirection in REDIRECTION_KEYS.iter() {
-            for red_i in 0..REDIRECTION_KEYS.len() {
-                let mut redirection = REDIRECTION_KEYS[red_i];
-                if command.contains(&redirection.to_string()) {
-                    // command > file 2>&1
-                    if command.contains(&">".to_string()) && command.contains(&"2>&1".to_string()) {
-                        // syntax error
-                        if command[command.len() - 1] != "2>&1" || command[command.len() - 3] != ">"
-                        {
-                            return Err("syntax error".to_string());
-                        }
-                        redirection = "&>";
-                        let redir_index = command.len() - 3;
-                        command[redir_index] = redirection.to_string();
-                        command.pop();
+
+    pub fn expand_arith(&mut self, parts: &[WordPart]) -> Result<i64, String> {
+        let mut fields = Fields::new(None);
+        self.expand_parts(parts, true, &mut fields)?;
+        self.eval_arith(&fields.finish().concat())
+    }
+
+    fn push_params(params: &[String], quoted: bool, fields: &mut Fields) {
+        for (i, param) in params.iter().enumerate() {
+            if i > 0 {
+                fields.end_param();
+            }
+            if quoted {
+                fields.push_text(param, true);
+            } else {
+                fields.push_expansion(param, false);
+            }
+        }
+    }
+
+    fn expand_param(&mut self, exp: &ParamExp, quoted: bool, fields: &mut Fields) -> Result<(), String> {
+        let name = exp.name.as_str();
+        // $@, $*, ${a[@]} and ${a[*]} are lists, the ones with * are joined into
+        // one field inside double quotes
+        let (list, star) = match (&exp.subscript, list_subscript(exp)) {
+            (Some(_), Some(star)) => (Some(self.elements(name)), star),
+            (None, _) if name == "@" || name == "*" => (Some(self.positional.clone()), name == "*"),
+            _ => (None, false),
+        };
+        let (value, set) = match (&list, &exp.subscript) {
+            (Some(list), _) => (self.join_list(list, star), !list.is_empty()),
+            (None, Some(subscript)) => match self.element(name, subscript)? {
+                Some(value) => (value, true),
+                None => (String::new(), false),
+            },
+            (None, None) => (self.variable(name), self.is_set(name)),
+        };
+        let value = match &exp.op {
+            ParamOp::Value => match list {
+                Some(list) => return self.push_list(&list, star, quoted, fields),
+                None => value,
+            },
+            ParamOp::Keys => {
+                let keys = self.keys(name);
+                return self.push_list(&keys, star, quoted, fields);
+            }
+            ParamOp::Length => match list {
+                Some(list) => list.len().to_string(),
+                None => value.chars().count().to_string(),
+            },
+            ParamOp::Default { colon, word } | ParamOp::Error { colon, word } if !set || (*colon && value.is_empty()) => {
+                if let ParamOp::Default { .. } = exp.op {
+                    return self.expand_param_word(word, quoted, fields);
+                }
+                // a script ends right away
+                if !self.interactive {
+                    self.time_to_exit = true;
+                }
+                if word.parts.is_empty() {
+                    return Err(format!("{}: parameter null or not set", exp.target()));
+                } else {
+                    return Err(format!("{}: {}", exp.target(), self.expand_string(word)?));
+                }
+            }
+            ParamOp::Default { .. } | ParamOp::Error { .. } => match list {
+                Some(list) => return self.push_list(&list, star, quoted, fields),
+                None => value,
+            },
+            ParamOp::Alternate { colon, word } => {
+                if set && !(*colon && value.is_empty()) {
+                    return self.expand_param_word(word, quoted, fields);
+                }
+                String::new()
+            }
+            ParamOp::Assign { colon, word } => {
+                if set && !(*colon && value.is_empty()) {
+                    match list {
+                        Some(list) => return self.push_list(&list, star, quoted, fields),
+                        None => value,
                     }
-                    let io_indecies = REDIRECTIONS.get(redirection).unwrap();
-                    let filename = command.last().unwrap();
-
-                    let fd: i32;
-                    if redirection == "<" {
-                        fd = match File::open(filename) {
-                            Ok(f) => f.into_raw_fd(),
-                            Err(err) => return Err(err.to_string()),
-                        }
-                    } else {
-                        fd = match File::create(filename) {
-                            Ok(f) => f.into_raw_fd(),
-                            Err(err) => return Err(err.to_string()),
+                } else if list.is_some() || !is_name(name) {
+                    return Err(format!("${}: cannot assign in this way", exp.target()));
+                } else {
+                    let value = self.expand_string(word)?;
+                    let target = match &exp.subscript {
+                        Some(subscript) => format!("{}[{}]", name, self.expand_string(subscript)?),
+                        None => name.to_string(),
+                    };
+                    self.assign(&format!("{}={}", target, value))?;
+                    value
+                }
+            }
+            ParamOp::Substring { offset, length } => {
+                let offset = self.expand_arith(&offset.parts)?;
+                let length = match length {
+                    Some(length) => Some(self.expand_arith(&length.parts)?),
+                    None => None,
+                };
+                match list {
+                    Some(mut list) => {
+                        // $0 comes before the positional parameters
+                        if exp.subscript.is_none() {
+                            list.insert(0, self.script_name.clone());
                         }
+                        let list = substring(&list, offset, length, name)?;
+                        return self.push_list(&list, star, quoted, fields);
                     }
-                    for &index in io_indecies {
-                        let old_desc = p.ioe_descriptors[step_i][index as usize];
-                        if old_desc > 2 {
-                            unsafe {
-                                if close(old_desc) == -1 {
-                                    return Err(
-                                        "file descriptor close was unsuccsessful".to_string()
-                                    );
-                                }
-                            };
-                        }
-                        p.ioe_descriptors[step_i][index as usize] = fd;
+                    None => {
+                        let chars: Vec<char> = value.chars().collect();
+                        substring(&chars, offset, length, name)?.iter().collect()
                     }
-                    if command.len() < 3 {
-                        return Err("parse error".to_string());
+                }
+            }
+            // the other operators change every element of a list
+            op => match list {
+                Some(list) => {
+                    let mut changed = Vec::new();
+                    for value in list {
+                        changed.push(self.change_value(op, value)?);
                     }
-                    // pop 2 last entries
-                    command.pop();
-                    command.pop();
-                    break;
+                    return self.push_list(&changed, star, quoted, fields);
+                }
+                None => self.change_value(op, value)?,
+            },
+        };
+        fields.push_expansion(&value, quoted);
+        Ok(())
+    }
+
+    // trims, replacements and case changes of a single value
+    fn change_value(&mut self, op: &ParamOp, value: String) -> Result<String, String> {
+        Ok(match op {
+            ParamOp::TrimPrefix { longest, pattern } => {
+                let pattern = self.param_pattern(pattern)?;
+                let mut ends = char_boundaries(&value);
+                if *longest {
+                    ends.reverse();
+                }
+                match ends.into_iter().find(|&end| pattern.matches(&value[..end])) {
+                    Some(end) => value[end..].to_string(),
+                    None => value,
                 }
             }
+            ParamOp::TrimSuffix { longest, pattern } => {
+                let pattern = self.param_pattern(pattern)?;
+                let mut starts = char_boundaries(&value);
+                if !*longest {
+                    starts.reverse();
+                }
+                match starts.into_iter().find(|&start| pattern.matches(&value[start..])) {
+                    Some(start) => value[..start].to_string(),
+                    None => value,
+                }
+            }
+            ParamOp::Replace { all, anchor, pattern, replacement } => {
+                let pattern = self.param_pattern(pattern)?;
+                let replacement = self.expand_string(replacement)?;
+                let bounds = char_boundaries(&value);
+                match anchor {
+                    Some('#') => match bounds.iter().rev().find(|&&end| pattern.matches(&value[..end])) {
+                        Some(&end) => replacement + &value[end..],
+                        None => value,
+                    },
+                    Some(_) => match bounds.iter().find(|&&start| pattern.matches(&value[start..])) {
+                        Some(&start) => value[..start].to_string() + &replacement,
+                        None => value,
+                    },
+                    None => replace_matches(&value, &pattern, &replacement, *all),
+                }
+            }
+            ParamOp::Upper { all } => change_case(&value, *all, |c| c.to_uppercase().collect()),
+            ParamOp::Lower { all } => change_case(&value, *all, |c| c.to_lowercase().collect()),
+            _ => value,
+        })
+    }
+
+    // every element is a field of its own, unless they are joined by "$*"
+    fn push_list(&self, list: &[String], star: bool, quoted: bool, fields: &mut Fields) -> Result<(), String> {
+        if star && quoted {
+            fields.push_expansion(&self.join_list(list, true), quoted);
+        } else {
+            MyShell::push_params(list, quoted, fields);
         }
+        Ok(())
+    }
 
-        Ok(p)
+    // $* is joined with the first character of IFS, $@ with spaces
+    fn join_list(&self, list: &[String], star: bool) -> String {
+        let separator = match star {
+            true => self.ifs().chars().next().map(String::from).unwrap_or_default(),
+            false => " ".to_string(),
+        };
+        list.join(&separator)
     }
-    pub fn substitute_vars_rem_parenth(
-        &self,
-        command: Result<Vec<String>, String>,
-    ) -> Result<Vec<String>, String> {
-        let command = command?;
-        let mut result: Vec<String> = Vec::new();
-        let mut new_token: String = String::new();
-        let mut substitue: bool = true;
-        let mut from: usize;
-        let mut to: usize;
-
-        for token in &command {
-            new_token.clear();
-            let token_chars: Vec<char> = token.chars().collect();
-
-            let mut i: usize = 0;
-            while i < token_chars.len() {
-                // escape symbol
-                if token_chars[i] == '\\' {
-                    if i != token.len() - 1 {
-                        new_token.push(token_chars[i + 1]);
-                        i += 2;
-                        continue;
-                    }
-                    i += 1;
-                // dont substitue variables between '
-                } else if token_chars[i] == '\'' {
-                    substitue = !substitue;
-                // variables substitution
-                } else if token_chars[i] == '$'
-                    && substitue
-                    && (i != token_chars.len() - 1 && token_chars[i + 1] != '(')
-                {
-                    from = i;
-                    to = token_chars.len();
-                    for &s in SPECIAL_SYMBOLS.iter() {
-                        if let Some(val) = token[from+1..].find(s) {
-                            to = val;
-                        }
-                    } 
-                    let varname = &token[from+1..to];
-                    if self.local_vars.contains_key(varname) {
-                        new_token += self.local_vars.get(varname).unwrap();
-                    } else if !env::var(varname).is_err() {
-                        new_token += &env::var(varname).unwrap();
-                    }
-                    i += varname.len();
-                } else if token_chars[i] != '"' {
-                    new_token.push(token_chars[i]);
-                }
-                i += 1;
+
+    // the word of ${name:-word} and ${name:+word}, its unquoted text is split
+    // like the value of a variable
+    fn expand_param_word(&mut self, word: &Word, quoted: bool, fields: &mut Fields) -> Result<(), String> {
+        for part in &word.parts {
+            match part {
+                WordPart::Lit(s) => fields.push_expansion(s, quoted),
+                part => self.expand_parts(std::slice::from_ref(part), quoted, fields)?,
             }
-            result.push(new_token.clone());
         }
+        Ok(())
+    }
 
-        Ok(result)
+    // the word of an operator as one string, without field splitting
+    pub fn expand_string(&mut self, word: &Word) -> Result<String, String> {
+        let mut fields = Fields::new(None);
+        self.expand_parts(&word.parts, false, &mut fields)?;
+        Ok(fields.finish().concat())
     }
 
-    pub fn expand_globs(command: Result<Vec<String>, String>) -> Result<Vec<String>, String> {
-        let mut command = command?;
-        let mut result: Vec<String> = vec![command[0].clone()];
+    fn param_pattern(&mut self, word: &Word) -> Result<Pattern, String> {
+        let pattern = self.expand_pattern(word)?;
+        Ok(Pattern::new(&pattern, self.glob_options.extglob))
+    }
+
+    // whether a parameter has a value, possibly an empty one
+    pub fn is_set(&self, name: &str) -> bool {
+        match name {
+            "@" | "*" => !self.positional.is_empty(),
+            "#" | "?" | "$" | "0" => true,
+            "!" => self.last_background.is_some(),
+            _ => match name.parse::<usize>() {
+                Ok(n) => n <= self.positional.len(),
+                Err(_) => {
+                    self.first_element(name).is_some() || self.local_vars.contains_key(name) || env::var_os(name).is_some()
+                }
+            },
+        }
+    }
+
+    // a case pattern, quoted parts only match themselves
+    pub fn expand_pattern(&mut self, word: &Word) -> Result<String, String> {
+        let mut fields = Fields::new(None);
+        self.expand_parts(&word.parts, false, &mut fields)?;
+        Ok(fields.finish_words().into_iter().map(|word| word.pattern).collect())
+    }
 
-        for i in 1..command.len() {
-            let entries = match glob(&command[i]) {
-                Ok(matches) => matches,
-                Err(err) => return Err(err.to_string()),
+    // local variables shadow the environment, unset ones are empty
+    pub fn variable(&self, name: &str) -> String {
+        match name {
+            "#" => return self.positional.len().to_string(),
+            "@" => return self.positional.join(" "),
+            // joined with the first character of IFS
+            "*" => {
+                let separator = self.ifs().chars().next().map(String::from).unwrap_or_default();
+                return self.positional.join(&separator);
+            }
+            "?" => return self.last_exit_code.to_string(),
+            "$" => return self.shell_pid.to_string(),
+            "!" => return self.last_background.map(|pid| pid.to_string()).unwrap_or_default(),
+            _ => {}
+        }
+        if let Ok(n) = name.parse::<usize>() {
+            return match n {
+                0 => self.script_name.clone(),
+                n => self.positional.get(n - 1).cloned().unwrap_or_default(),
             };
-            let mut matched = false;
-            for entry in entries {
-                matched = true;
-                match entry {
-                    Ok(path) => result.push(match path.to_str() {
-                        Some(path) => path.to_string(),
-                        None => return Err("glob exeption".to_string()),
-                    }),
-                    Err(err) => return Err(err.to_string()),
+        }
+        if self.arrays.contains_key(name) {
+            return self.first_element(name).unwrap_or_default();
+        }
+        match self.local_vars.get(name) {
+            Some(value) => value.clone(),
+            None => env::var(name).unwrap_or_default(),
+        }
+    }
+
+    fn ifs(&self) -> String {
+        match self.local_vars.get("IFS") {
+            Some(value) => value.clone(),
+            None => env::var("IFS").unwrap_or_else(|_| DEFAULT_IFS.to_string()),
+        }
+    }
+
+    // runs `source` in a forked copy of the shell and returns what it printed,
+    // without trailing newlines
+    fn command_output(&mut self, source: &str) -> Result<String, String> {
+        let (read_end, write_end) = pipe2(OFlag::O_CLOEXEC).map_err(|err| err.to_string())?;
+        // the child would print whatever is still buffered again
+        io::stdout().flush().map_err(|err| err.to_string())?;
+        match unsafe { fork() } {
+            Ok(ForkResult::Child) => {
+                if dup2(write_end, STDOUT_FILENO).is_err() {
+                    process::exit(1);
+                }
+                let status = self.run_line(&mut source.to_string());
+                process::exit(status);
+            }
+            Ok(ForkResult::Parent { child }) => {
+                let _ = unistd::close(write_end);
+                let mut output = Vec::new();
+                let read_result = unsafe { File::from_raw_fd(read_end) }.read_to_end(&mut output);
+                let status = wait_for(child)?;
+                read_result.map_err(|err| err.to_string())?;
+                self.last_exit_code = status;
+                let mut output = String::from_utf8_lossy(&output).into_owned();
+                while output.ends_with('\n') {
+                    output.pop();
+                }
+                Ok(output)
+            }
+            Err(err) => {
+                let _ = unistd::close(read_end);
+                let _ = unistd::close(write_end);
+                Err(format!("fork() failed: {}", err))
+            }
+        }
+    }
+
+    // Runs `source` in a copy of the shell with its stdout, or its stdin for
+    // >(...), on a pipe and returns the name of the other end. That end stays
+    // open, for the programs to inherit, until `close_substitutions`.
+    fn process_substitution(&mut self, source: &str, output: bool) -> Result<String, String> {
+        let (read_end, write_end) = pipe2(OFlag::O_CLOEXEC).map_err(|err| err.to_string())?;
+        let (kept, given, fd) = if output {
+            (write_end, read_end, STDIN_FILENO)
+        } else {
+            (read_end, write_end, STDOUT_FILENO)
+        };
+        io::stdout().flush().map_err(|err| err.to_string())?;
+        match unsafe { fork() } {
+            Ok(ForkResult::Child) => {
+                if dup2(given, fd).is_err() {
+                    process::exit(1);
                 }
+                let _ = unistd::close(given);
+                let _ = unistd::close(kept);
+                // It lives as long as the command it talks to, so it can't hold the
+                // pipes of that pipeline or the ends of other substitutions open.
+                // Its commands are part of the audited line already.
+                self.audit = None;
+                self.substitutions.clear();
+                close_private();
+                let status = self.run_line(&mut source.to_string());
+                process::exit(status);
+            }
+            Ok(ForkResult::Parent { child }) => {
+                let _ = unistd::close(given);
+                let desc = inherited_fd(kept)?;
+                self.substitutions.push((desc, child));
+                Ok(format!("/dev/fd/{}", desc))
             }
-            if !matched {
-                result.push(command[i].clone());
+            Err(err) => {
+                let _ = unistd::close(read_end);
+                let _ = unistd::close(write_end);
+                Err(format!("fork() failed: {}", err))
             }
         }
-        command = result;
+    }
+
+    // closes the pipes of the substitutions made since there were `count` of
+    // them, and waits for the commands unless they went to the background
+    pub fn close_substitutions(&mut self, count: usize, wait: bool) {
+        let substitutions = self.substitutions.split_off(count.min(self.substitutions.len()));
+        for &(desc, _) in &substitutions {
+            let _ = unistd::close(desc);
+        }
+        if wait {
+            for (_, child) in substitutions {
+                let _ = wait_for(child);
+            }
+        }
+    }
+
+    // the command name itself is never a pattern
+    pub fn expand_globs(&self, words: Result<Vec<ExpandedWord>, String>) -> Result<Vec<String>, String> {
+        let mut words = words?.into_iter();
+        let mut command: Vec<String> = words.next().map(|word| word.text).into_iter().collect();
+        command.extend(self.glob_all(words.collect())?);
         Ok(command)
     }
 
+    // Words without matches stay as they are, unless nullglob removes them or
+    // failglob makes them an error. Quoted characters only match themselves.
+    pub fn glob_all(&self, words: Vec<ExpandedWord>) -> Result<Vec<String>, String> {
+        let options = &self.glob_options;
+        let mut result = Vec::new();
+        for word in words {
+            if Pattern::new(&word.pattern, options.extglob).is_literal() {
+                result.push(word.text);
+                continue;
+            }
+            let paths = expand_pathname(&word.pattern, options);
+            if !paths.is_empty() {
+                result.extend(paths);
+            } else if options.failglob {
+                return Err(format!("no match: {}", word.text));
+            } else if !options.nullglob {
+                result.push(word.text);
+            }
+        }
+        Ok(result)
+    }
+
     pub fn insert_myshell(command: Result<Vec<String>, String>) -> Result<Vec<String>, String> {
         let mut command = command?;
         if command.len() == 1 && command[0].ends_with(".msh") {
@@ -288,7 +782,15 @@ impl MyShell {
     pub fn mark_command_types(&self, mut p: Pipeline) -> Pipeline {
         for i in 0..p.steps.len() {
             let command = &p.steps[i];
-            p.types[i] = if self.internal_cmds.contains(&command[0].as_str()) {
+            if p.types[i] == CommandType::Compound {
+                continue;
+            }
+            // nothing left after expansion, there is nothing to start
+            p.types[i] = if command.is_empty() {
+                CommandType::Empty
+            } else if self.functions.contains_key(&command[0]) {
+                CommandType::Function
+            } else if self.internal_cmds.contains(&command[0].as_str()) {
                 CommandType::Internal
             } else if command.len() == 1 && command[0].contains('=') {
                 CommandType::LocalVar
@@ -300,3 +802,83 @@ impl MyShell {
     }
 
 }
+
+// Some(false) for ${name[@]}, Some(true) for ${name[*]}
+fn list_subscript(exp: &ParamExp) -> Option<bool> {
+    match exp.subscript.as_ref().and_then(Word::literal).as_deref() {
+        Some("@") => Some(false),
+        Some("*") => Some(true),
+        _ => None,
+    }
+}
+
+fn char_boundaries(value: &str) -> Vec<usize> {
+    value.char_indices().map(|(i, _)| i).chain(std::iter::once(value.len())).collect()
+}
+
+// every match starts where the previous one ended and is as long as possible
+fn replace_matches(value: &str, pattern: &Pattern, replacement: &str, all: bool) -> String {
+    let mut result = String::new();
+    let mut rest = value;
+    while !rest.is_empty() {
+        let end = char_boundaries(rest).into_iter().skip(1).rev().find(|&end| pattern.matches(&rest[..end]));
+        match end {
+            Some(end) => {
+                result += replacement;
+                rest = &rest[end..];
+                if !all {
+                    break;
+                }
+            }
+            None => {
+                let next = rest.chars().next().map(char::len_utf8).unwrap_or(1);
+                result += &rest[..next];
+                rest = &rest[next..];
+            }
+        }
+    }
+    result + rest
+}
+
+// negative offsets count from the end, a negative length stops that far from it
+fn substring<T: Clone>(items: &[T], offset: i64, length: Option<i64>, name: &str) -> Result<Vec<T>, String> {
+    let len = items.len() as i64;
+    let start = if offset < 0 { len.saturating_add(offset).max(0) } else { offset.min(len) };
+    let end = match length {
+        None => len,
+        Some(length) if length < 0 => len.saturating_add(length),
+        Some(length) => start.saturating_add(length).min(len),
+    };
+    if end < start {
+        return Err(format!("{}: substring expression < 0", name));
+    }
+    Ok(items[start as usize..end as usize].to_vec())
+}
+
+fn change_case(value: &str, all: bool, change: impl Fn(char) -> String) -> String {
+    let mut chars = value.chars();
+    match chars.next() {
+        Some(_) if all => value.chars().map(change).collect(),
+        Some(first) => change(first) + chars.as_str(),
+        None => String::new(),
+    }
+}
+
+// A descriptor to read `text` from. It is kept in a temporary file removed
+// right away, a pipe could not hold a long text before the command reads it.
+fn here_document(text: &str) -> Result<i32, String> {
+    let mut n = 0;
+    let (mut file, path) = loop {
+        let path = env::temp_dir().join(format!("myshell-here-{}-{}", process::id(), n));
+        match OpenOptions::new().read(true).write(true).create_new(true).mode(0o600).open(&path) {
+            Ok(file) => break (file, path),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists && n < 100 => n += 1,
+            Err(err) => return Err(format!("cannot create temp file for here-document: {}", err)),
+        }
+    };
+    let _ = fs::remove_file(&path);
+    file.write_all(text.as_bytes())
+        .and_then(|_| file.seek(SeekFrom::Start(0)))
+        .map_err(|err| format!("cannot write here-document: {}", err))?;
+    Ok(file.into_raw_fd())
+}
@@ -0,0 +1,238 @@
+use std::io::{self, Read, Write};
+
+// Every frame is: <type: u8><payload length: u32 big endian><payload>.
+// The first frame in both directions is Hello, the server answers a Hello
+// with an incompatible version with Error and closes the connection.
+// After the hello the client authenticates with Auth and, once the server
+// answers AuthOk, sends StartSession, optionally asking for a terminal.
+pub const PROTOCOL_VERSION: u16 = 4;
+const HELLO_MAGIC: &[u8; 4] = b"MYSH";
+const HEADER_LEN: usize = 5;
+const MAX_PAYLOAD_LEN: usize = 1 << 20;
+
+const HELLO: u8 = 1;
+const INPUT: u8 = 2;
+const STDOUT: u8 = 3;
+const STDERR: u8 = 4;
+const EXIT_STATUS: u8 = 5;
+const PROMPT: u8 = 6;
+const CLOSE: u8 = 7;
+const ERROR: u8 = 8;
+const START_SESSION: u8 = 9;
+const WINDOW_SIZE: u8 = 10;
+const AUTH: u8 = 11;
+const AUTH_OK: u8 = 12;
+const SHUTDOWN: u8 = 13;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    Hello { version: u16 },
+    // client -> server: command lines and stdin of running programs
+    Input(Vec<u8>),
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+    // status of every finished command line
+    ExitStatus(i32),
+    Prompt(String),
+    // client: no more input; server: session finished with the status
+    Close(i32),
+    // fatal, the connection is closed after it
+    Error(String),
+    // terminal size (rows, columns) when the client wants a pty
+    StartSession(Option<(u16, u16)>),
+    // client terminal was resized, pty sessions only
+    WindowSize(u16, u16),
+    // shared secret of the server
+    Auth(Vec<u8>),
+    AuthOk,
+    // the server is stopping, the session is closed in that many seconds
+    Shutdown(u32),
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let (msg_type, payload): (u8, Vec<u8>) = match self {
+            Message::Hello { version } => {
+                let mut payload = HELLO_MAGIC.to_vec();
+                payload.extend_from_slice(&version.to_be_bytes());
+                (HELLO, payload)
+            }
+            Message::Input(data) => (INPUT, data.clone()),
+            Message::Stdout(data) => (STDOUT, data.clone()),
+            Message::Stderr(data) => (STDERR, data.clone()),
+            Message::ExitStatus(status) => (EXIT_STATUS, status.to_be_bytes().to_vec()),
+            Message::Prompt(prompt) => (PROMPT, prompt.as_bytes().to_vec()),
+            Message::Close(status) => (CLOSE, status.to_be_bytes().to_vec()),
+            Message::Error(message) => (ERROR, message.as_bytes().to_vec()),
+            Message::StartSession(None) => (START_SESSION, Vec::new()),
+            Message::StartSession(Some((rows, cols))) => (START_SESSION, encode_size(*rows, *cols)),
+            Message::WindowSize(rows, cols) => (WINDOW_SIZE, encode_size(*rows, *cols)),
+            Message::Auth(token) => (AUTH, token.clone()),
+            Message::AuthOk => (AUTH_OK, Vec::new()),
+            Message::Shutdown(grace) => (SHUTDOWN, grace.to_be_bytes().to_vec()),
+        };
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.push(msg_type);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    fn decode(msg_type: u8, payload: &[u8]) -> Result<Message, String> {
+        let status = || -> Result<i32, String> {
+            match <[u8; 4]>::try_from(payload) {
+                Ok(bytes) => Ok(i32::from_be_bytes(bytes)),
+                Err(_) => Err("protocol error: bad status frame".to_string()),
+            }
+        };
+        let text = || String::from_utf8_lossy(payload).into_owned();
+        let size = || -> Result<(u16, u16), String> {
+            if payload.len() != 4 {
+                return Err("protocol error: bad window size".to_string());
+            }
+            Ok((
+                u16::from_be_bytes([payload[0], payload[1]]),
+                u16::from_be_bytes([payload[2], payload[3]]),
+            ))
+        };
+        Ok(match msg_type {
+            HELLO => {
+                if payload.len() != HELLO_MAGIC.len() + 2 || &payload[..4] != HELLO_MAGIC {
+                    return Err("protocol error: peer is not a myshell".to_string());
+                }
+                Message::Hello {
+                    version: u16::from_be_bytes([payload[4], payload[5]]),
+                }
+            }
+            INPUT => Message::Input(payload.to_vec()),
+            STDOUT => Message::Stdout(payload.to_vec()),
+            STDERR => Message::Stderr(payload.to_vec()),
+            EXIT_STATUS => Message::ExitStatus(status()?),
+            PROMPT => Message::Prompt(text()),
+            CLOSE => Message::Close(status()?),
+            ERROR => Message::Error(text()),
+            START_SESSION if payload.is_empty() => Message::StartSession(None),
+            START_SESSION => Message::StartSession(Some(size()?)),
+            WINDOW_SIZE => {
+                let (rows, cols) = size()?;
+                Message::WindowSize(rows, cols)
+            }
+            AUTH => Message::Auth(payload.to_vec()),
+            AUTH_OK => Message::AuthOk,
+            SHUTDOWN => Message::Shutdown(status()? as u32),
+            _ => return Err(format!("protocol error: unknown frame type {}", msg_type)),
+        })
+    }
+}
+
+fn encode_size(rows: u16, cols: u16) -> Vec<u8> {
+    let mut payload = rows.to_be_bytes().to_vec();
+    payload.extend_from_slice(&cols.to_be_bytes());
+    payload
+}
+
+// accumulates raw bytes and cuts them into messages, frames may be split arbitrarily
+#[derive(Default)]
+pub struct FrameReader {
+    buf: Vec<u8>,
+}
+
+impl FrameReader {
+    pub fn new() -> FrameReader {
+        FrameReader { buf: Vec::new() }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn next_message(&mut self) -> Result<Option<Message>, String> {
+        if self.buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([self.buf[1], self.buf[2], self.buf[3], self.buf[4]]) as usize;
+        if len > MAX_PAYLOAD_LEN {
+            return Err("protocol error: frame is too large".to_string());
+        }
+        if self.buf.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+        let message = Message::decode(self.buf[0], &self.buf[HEADER_LEN..HEADER_LEN + len]);
+        self.buf.drain(..HEADER_LEN + len);
+        message.map(Some)
+    }
+
+    // blocks until a whole message arrives, None on a clean end of stream
+    pub fn read_message(&mut self, stream: &mut impl Read) -> Result<Option<Message>, String> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            if let Some(message) = self.next_message()? {
+                return Ok(Some(message));
+            }
+            let n = match stream.read(&mut chunk) {
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.to_string()),
+            };
+            if n == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err("protocol error: connection closed in the middle of a frame".to_string());
+            }
+            self.push(&chunk[..n]);
+        }
+    }
+}
+
+pub fn write_message(stream: &mut impl Write, message: &Message) -> Result<(), String> {
+    stream
+        .write_all(&message.encode())
+        .and_then(|_| stream.flush())
+        .map_err(|err| err.to_string())
+}
+
+pub fn client_handshake<S: Read + Write>(
+    stream: &mut S,
+    reader: &mut FrameReader,
+    token: &[u8],
+) -> Result<(), String> {
+    write_message(stream, &Message::Hello { version: PROTOCOL_VERSION })?;
+    match reader.read_message(stream)? {
+        Some(Message::Hello { version }) if version == PROTOCOL_VERSION => {}
+        Some(Message::Hello { version }) => {
+            return Err(format!(
+                "server speaks protocol version {}, client speaks {}",
+                version, PROTOCOL_VERSION
+            ))
+        }
+        Some(Message::Error(err)) => return Err(err),
+        Some(_) => return Err("protocol error: expected hello from server".to_string()),
+        None => return Err("connection closed by server".to_string()),
+    }
+    write_message(stream, &Message::Auth(token.to_vec()))?;
+    match reader.read_message(stream)? {
+        Some(Message::AuthOk) => Ok(()),
+        Some(Message::Error(err)) => Err(err),
+        Some(_) => Err("protocol error: expected authentication result".to_string()),
+        None => Err("connection closed by server".to_string()),
+    }
+}
+
+pub fn server_handshake<S: Read + Write>(stream: &mut S, reader: &mut FrameReader) -> Result<(), String> {
+    match reader.read_message(stream)? {
+        Some(Message::Hello { version }) if version == PROTOCOL_VERSION => {
+            write_message(stream, &Message::Hello { version: PROTOCOL_VERSION })
+        }
+        Some(Message::Hello { version }) => {
+            let err = format!(
+                "unsupported protocol version {}, server speaks {}",
+                version, PROTOCOL_VERSION
+            );
+            write_message(stream, &Message::Error(err.clone())).unwrap_or(());
+            Err(err)
+        }
+        Some(_) => Err("protocol error: expected hello from client".to_string()),
+        None => Err("connection closed by client".to_string()),
+    }
+}
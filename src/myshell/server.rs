@@ -1,62 +1,995 @@
+use super::audit::Field;
+use super::auth::{
+    load_or_create_token, report_auth_failure, tokens_match, AuthLimiter, AUTH_FAILURE_DELAY,
+};
+use super::connection::{Connection, Listener, Peer, DEFAULT_SOCKET_MODE};
+use super::descriptors::private_file;
+use super::parser::LineBuffer;
+use super::protocol::{server_handshake, write_message, FrameReader, Message};
+use super::tls::{server_config, Identity};
+use super::utils::{load_history, read_line_fd, result_pathbuf_to_string};
 use super::MyShell;
-use argparse::Parse;
-use nix::sys::wait::wait;
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::libc::{__errno_location, c_int, ioctl, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO, TIOCSCTTY, TIOCSWINSZ};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::pty::{openpty, Winsize};
+use nix::sys::signal::{kill, sigaction, signal, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::ForkResult::{Child, Parent};
-use nix::unistd::{fork, getpid, getppid};
-use std::net::{TcpListener, TcpStream};
+use nix::unistd::{close, dup2, fork, getpid, pipe2, read, setpgid, setsid, write, Pid};
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::process;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
+// how long a rejected client gets to send its hello
+const REJECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+// sessions still running this long after the grace period are killed
+const SHUTDOWN_KILL_MARGIN: Duration = Duration::from_secs(2);
 
-const LOCALHOST: &'static str = "127.0.0.1";
+// signal handlers write to this pipe to wake up the poll of the accept loop
+static WAKEUP_FD: AtomicI32 = AtomicI32::new(-1);
+// SIGTERM or SIGINT in the server, SIGTERM in a connection process
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+pub struct ServerConfig {
+    pub port: String,
+    // IPv4 or IPv6 address to listen on
+    pub bind: String,
+    // when set, listen on this Unix domain socket instead of TCP
+    pub socket: Option<String>,
+    // permissions of the socket file, they decide who may connect
+    pub socket_mode: u32,
+    // 0 for no limit, connections past it are turned away
+    pub max_sessions: usize,
+    // sessions without input or output for this long are closed
+    pub idle_timeout: Option<Duration>,
+    // on shutdown running sessions get this long to finish
+    pub shutdown_grace: Duration,
+    // certificate and key of the server, enables TLS
+    pub tls_identity: Option<Identity>,
+    // CA client certificates must be signed by, such clients need no token
+    pub tls_client_ca: Option<String>,
+    // shared secret clients have to present, generated on the first start
+    pub token_file: String,
+}
+
+impl ServerConfig {
+    pub fn new(port: String, bind: String, token_file: String) -> ServerConfig {
+        ServerConfig {
+            port,
+            bind,
+            socket: None,
+            socket_mode: DEFAULT_SOCKET_MODE,
+            max_sessions: 0,
+            idle_timeout: None,
+            shutdown_grace: DEFAULT_SHUTDOWN_GRACE,
+            tls_identity: None,
+            tls_client_ca: None,
+            token_file,
+        }
+    }
+}
 
 impl MyShell {
-    pub fn start_server(&mut self, port: String) -> i32 {
-        let address = String::new() + LOCALHOST + ":" + &port;
-        let listener = match TcpListener::bind(&address) {
+    pub fn start_server(&mut self, config: ServerConfig) -> i32 {
+        let listener = match &config.socket {
+            Some(path) => Listener::bind_unix(path, config.socket_mode),
+            None => Listener::bind_tcp(&config.bind, &config.port),
+        };
+        let listener = match listener {
             Ok(l) => l,
             Err(err) => {
-                eprintln!("myshell: {:?}", err);
+                eprintln!("myshell: {}", err);
                 return 1;
             }
         };
-        println!("Start server at {}", address);
-        loop {
-            match listener.accept() {
-                Ok((client_stream, client_addr)) => {
-                    println!("Connection from {:?} accepted", client_addr);
-                    unsafe {
-                        let pid = fork();
-                        match pid.expect("myshell: fork() failed") {
-                            Child => {
-                                println!(
-                                    "Hello from child process with pid: {} and parent pid:{}",
-                                    getpid(),
-                                    getppid()
-                                );
-                                process::exit(MyShell::start_remote_interpreter(client_stream));
-                            }
-                            Parent { child } => {
-                                println!(
-                                    "Hello from parent process with pid: {} and child pid:{}",
-                                    getpid(),
-                                    child
-                                );
-                            }
+        let tls = match &config.tls_identity {
+            Some(identity) => match server_config(identity, config.tls_client_ca.as_deref()) {
+                Ok(c) => Some(c),
+                Err(err) => {
+                    eprintln!("myshell: {}", err);
+                    return 1;
+                }
+            },
+            None => None,
+        };
+        // on a Unix socket the permissions of the socket file control access
+        let token = if config.socket.is_some() {
+            Vec::new()
+        } else {
+            match load_or_create_token(&config.token_file) {
+                Ok(t) => t,
+                Err(err) => {
+                    eprintln!("myshell: {}", err);
+                    return 1;
+                }
+            }
+        };
+        // session processes report failed authentication attempts here
+        let (report_r, report_w) = match pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK) {
+            Ok(fds) => fds,
+            Err(err) => {
+                eprintln!("myshell: {}", err);
+                return 1;
+            }
+        };
+        let mut limiter = AuthLimiter::new(report_r);
+
+        // SIGCHLD and shutdown requests wake up the accept loop through this pipe
+        let (wakeup_r, wakeup_w) = match pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK) {
+            Ok(fds) => fds,
+            Err(err) => {
+                eprintln!("myshell: {}", err);
+                return 1;
+            }
+        };
+        WAKEUP_FD.store(wakeup_w, Ordering::SeqCst);
+        let on_child = SigAction::new(
+            SigHandler::Handler(on_child_exit),
+            SaFlags::SA_RESTART | SaFlags::SA_NOCLDSTOP,
+            SigSet::empty(),
+        );
+        let on_shutdown = SigAction::new(SigHandler::Handler(on_shutdown_request), SaFlags::SA_RESTART, SigSet::empty());
+        let setup = unsafe {
+            sigaction(Signal::SIGCHLD, &on_child)
+                .and_then(|_| sigaction(Signal::SIGTERM, &on_shutdown))
+                .and_then(|_| sigaction(Signal::SIGINT, &on_shutdown))
+        }
+        .map_err(|err| err.to_string())
+            .and_then(|_| listener.set_nonblocking().map_err(|err| err.to_string()));
+        if let Err(err) = setup {
+            eprintln!("myshell: {}", err);
+            return 1;
+        }
+        // the forked connection processes must not hold any of the server's descriptors
+        let server_fds = [listener.as_raw_fd(), report_r, wakeup_r, wakeup_w];
+        let mut sessions: HashMap<Pid, SessionInfo> = HashMap::new();
+        let mut served = 0;
+
+        println!("Start server at {}", listener.address());
+        match &config.socket {
+            Some(_) => println!("Clients are authorized by the permissions {:o} of the socket", config.socket_mode),
+            None => println!("Clients authenticate with the token from {}", config.token_file),
+        }
+        if tls.is_some() {
+            println!("Connections are encrypted with TLS");
+        }
+        if let Some(ca) = &config.tls_client_ca {
+            println!("Clients with a certificate signed by {} need no token", ca);
+        }
+        if config.max_sessions > 0 {
+            println!("At most {} sessions run at the same time", config.max_sessions);
+        }
+        if let Some(timeout) = config.idle_timeout {
+            println!("Sessions idle for {}s are closed", timeout.as_secs());
+        }
+        self.audit(
+            "server_start",
+            &[("address", Field::Str(&listener.address())), ("tls", Field::Int(tls.is_some() as i64))],
+        );
+        while !SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+            let mut poll_fds = [
+                PollFd::new(listener.as_raw_fd(), PollFlags::POLLIN),
+                PollFd::new(wakeup_r, PollFlags::POLLIN),
+            ];
+            match poll(&mut poll_fds, -1) {
+                Ok(_) => {}
+                Err(Errno::EINTR) => continue,
+                Err(err) => {
+                    eprintln!("myshell: {}", err);
+                    return 1;
+                }
+            }
+            let ready = |i: usize| poll_fds[i].revents().is_some_and(|r| !r.is_empty());
+            if ready(1) {
+                drain_wakeups(wakeup_r);
+                reap_sessions(&mut sessions);
+            }
+            if !ready(0) || SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+                continue;
+            }
+            let client_stream = match listener.accept() {
+                Ok(s) => s,
+                Err(err) if is_transient_accept_error(&err) => continue,
+                Err(err) => {
+                    eprintln!("myshell: {}", err);
+                    return 1;
+                }
+            };
+            let peer = match client_stream.peer() {
+                Ok(p) => p,
+                Err(err) => {
+                    eprintln!("myshell: {}", err);
+                    continue;
+                }
+            };
+            println!("Connection from {} accepted", peer);
+            limiter.collect_reports();
+            if peer.ip().is_some_and(|ip| limiter.is_blocked(&ip)) {
+                let reason = "too many failed authentication attempts, try again later";
+                self.audit_rejection(&peer, reason);
+                reject_client(client_stream, tls.clone(), &peer, reason, &server_fds);
+                continue;
+            }
+            if config.max_sessions > 0 && sessions.len() >= config.max_sessions {
+                let reason = format!("server is busy: {} sessions running, try again later", sessions.len());
+                self.audit_rejection(&peer, &reason);
+                reject_client(client_stream, tls.clone(), &peer, &reason, &server_fds);
+                continue;
+            }
+            match unsafe { fork() } {
+                Ok(Child) => {
+                    leave_server(&server_fds);
+                    let client_stream = match tls {
+                        Some(tls) => client_stream.accept_tls(tls),
+                        None => Ok(client_stream),
+                    };
+                    match client_stream {
+                        Ok(s) => process::exit(self.start_remote_interpreter(s, &config, &token, report_w)),
+                        Err(err) => {
+                            eprintln!("myshell: {}", err);
+                            process::exit(1);
                         }
                     }
                 }
+                Ok(Parent { child }) => {
+                    println!("Session {} started for {}", child, peer);
+                    served += 1;
+                    sessions.insert(
+                        child,
+                        SessionInfo {
+                            peer,
+                            started: Instant::now(),
+                        },
+                    );
+                }
+                Err(err) => eprintln!("myshell: fork() failed: {}", err),
+            }
+        }
+
+        // stop accepting, then give the running sessions the grace period
+        drop(listener);
+        if let Some(path) = &config.socket {
+            fs::remove_file(path).unwrap_or(());
+        }
+        println!("Shutting down, {} sessions running", sessions.len());
+        for pid in sessions.keys() {
+            kill(*pid, Signal::SIGTERM).unwrap_or(());
+        }
+        let running = sessions.len();
+        let deadline = Instant::now() + config.shutdown_grace + SHUTDOWN_KILL_MARGIN;
+        while !sessions.is_empty() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            let mut poll_fds = [PollFd::new(wakeup_r, PollFlags::POLLIN)];
+            match poll(&mut poll_fds, left.as_millis() as i32 + 1) {
+                Ok(_) | Err(Errno::EINTR) => {}
                 Err(err) => {
-                    eprintln!("Error: {:?}", err);
-                    return 1;
+                    eprintln!("myshell: {}", err);
+                    break;
+                }
+            }
+            drain_wakeups(wakeup_r);
+            reap_sessions(&mut sessions);
+        }
+        let killed = sessions.len();
+        for pid in sessions.keys() {
+            eprintln!("myshell: killing session {} for {}", pid, sessions[pid].peer);
+            kill(*pid, Signal::SIGKILL).unwrap_or(());
+        }
+        while !sessions.is_empty() {
+            let pid = *sessions.keys().next().unwrap();
+            waitpid(pid, None).unwrap_or(WaitStatus::StillAlive);
+            sessions.remove(&pid);
+        }
+        println!(
+            "Server stopped: {} sessions served, {} of {} running sessions finished, {} killed",
+            served,
+            running - killed,
+            running,
+            killed
+        );
+        self.audit(
+            "server_stop",
+            &[("served", Field::Int(served)), ("killed", Field::Int(killed as i64))],
+        );
+        if killed > 0 {
+            1
+        } else {
+            0
+        }
+    }
+
+    fn audit_rejection(&mut self, peer: &Peer, reason: &str) {
+        let peer = peer.to_string();
+        self.audit("rejected", &[("peer", Field::Str(&peer)), ("reason", Field::Str(reason))]);
+    }
+
+    pub fn start_remote_interpreter(
+        &mut self,
+        client_stream: Connection,
+        config: &ServerConfig,
+        token: &[u8],
+        report_fd: i32,
+    ) -> i32 {
+        let started = Instant::now();
+        if let Some(audit) = &mut self.audit {
+            let peer = client_stream.peer().map(|p| p.to_string()).unwrap_or_default();
+            audit.start_session(&peer);
+        }
+        self.audit("connect", &[]);
+        let status = self.serve_client(client_stream, config, token, report_fd);
+        self.audit(
+            "disconnect",
+            &[
+                ("status", Field::Int(status as i64)),
+                ("duration_ms", Field::Int(started.elapsed().as_millis() as i64)),
+            ],
+        );
+        status
+    }
+
+    fn serve_client(&mut self, mut client_stream: Connection, config: &ServerConfig, token: &[u8], report_fd: i32) -> i32 {
+        let mut from_client = FrameReader::new();
+        // a client that stops talking during the handshake is idle as well
+        client_stream.set_read_timeout(config.idle_timeout).unwrap_or(());
+        let handshake = server_handshake(&mut client_stream, &mut from_client)
+            .and_then(|_| authenticate_client(&mut client_stream, &mut from_client, token, report_fd));
+        match handshake {
+            Ok(method) => self.audit("auth", &[("result", Field::Str("ok")), ("method", Field::Str(method))]),
+            Err(err) => {
+                self.audit("auth", &[("result", Field::Str("failed")), ("reason", Field::Str(&err))]);
+                eprintln!("myshell: {}", err);
+                return 1;
+            }
+        }
+        let tty_size = match from_client.read_message(&mut client_stream) {
+            Ok(Some(Message::StartSession(size))) => size,
+            Ok(_) => {
+                let err = "protocol error: expected session start".to_string();
+                eprintln!("myshell: {}", err);
+                write_message(&mut client_stream, &Message::Error(err)).unwrap_or(());
+                return 1;
+            }
+            Err(err) => {
+                eprintln!("myshell: {}", err);
+                return 1;
+            }
+        };
+
+        self.audit("session_start", &[("tty", Field::Int(tty_size.is_some() as i64))]);
+
+        // the session sends its prompts and statuses through a close-on-exec control pipe,
+        // so it reaches EOF exactly when the session process exits
+        let open_stdio = || -> Result<((i32, i32), SessionStdio), String> {
+            let control = pipe2(OFlag::O_CLOEXEC).map_err(|err| err.to_string())?;
+            Ok((control, SessionStdio::open(tty_size)?))
+        };
+        let ((ctl_r, ctl_w), stdio) = match open_stdio() {
+            Ok(fds) => fds,
+            Err(err) => {
+                eprintln!("myshell: {}", err);
+                return 1;
+            }
+        };
+
+        match unsafe { fork() } {
+            Ok(Child) => {
+                drop(client_stream);
+                close(ctl_r).unwrap_or(());
+                if let Err(err) = stdio.attach() {
+                    eprintln!("myshell: {}", err);
+                    process::exit(1);
+                }
+                process::exit(self.run_remote_session(ctl_w, tty_size.is_some()));
+            }
+            Ok(Parent { child }) => {
+                close(ctl_w).unwrap_or(());
+                // the server asks to wind down with SIGTERM, the relay tells the client
+                let on_shutdown = SigAction::new(SigHandler::Handler(on_shutdown_request), SaFlags::empty(), SigSet::empty());
+                unsafe { sigaction(Signal::SIGTERM, &on_shutdown) }.unwrap_or(on_shutdown);
+                client_stream.set_read_timeout(None).unwrap_or(());
+                let (in_w, out_r, err_r, pty) = stdio.relay_ends();
+                let mut relay = SessionRelay {
+                    stream: client_stream,
+                    from_client,
+                    session: child,
+                    in_w,
+                    to_session: Vec::new(),
+                    close_stdin: false,
+                    out_r,
+                    err_r,
+                    pty,
+                    ctl_r,
+                    control: FrameReader::new(),
+                    idle_timeout: config.idle_timeout,
+                    last_activity: Instant::now(),
+                    shutdown_grace: config.shutdown_grace,
+                    shutdown_deadline: None,
+                };
+                relay.run()
+            }
+            Err(err) => {
+                eprintln!("myshell: fork() failed: {}", err);
+                1
+            }
+        }
+    }
+
+    // runs in the forked session process, its stdio are connected to the relay
+    fn run_remote_session(&mut self, control_fd: i32, tty: bool) -> i32 {
+        let mut control = private_file(unsafe { File::from_raw_fd(control_fd) });
+        // the session is a shell of its own
+        self.shell_pid = getpid().as_raw();
+        // on a terminal the session edits lines itself like `start_int_shell`,
+        // otherwise the client does it and gets prompts as frames
+        let mut rl = Editor::<()>::new();
+        let history_filename = if tty {
+            // ^C and ^\ are meant for the running program, not for the session
+            unsafe {
+                signal(Signal::SIGINT, SigHandler::SigIgn).unwrap_or(SigHandler::SigDfl);
+                signal(Signal::SIGQUIT, SigHandler::SigIgn).unwrap_or(SigHandler::SigDfl);
+                // there is no job control to resume stopped programs
+                signal(Signal::SIGTSTP, SigHandler::SigIgn).unwrap_or(SigHandler::SigDfl);
+            }
+            Some(load_history(&mut rl))
+        } else {
+            None
+        };
+
+        self.interactive = true;
+        let mut lines = LineBuffer::default();
+        while !self.time_to_exit {
+            let prompt = if lines.is_empty() {
+                result_pathbuf_to_string(env::current_dir()) + " $ "
+            } else {
+                String::from("> ")
+            };
+            let line = if tty {
+                match rl.readline(&prompt) {
+                    Ok(l) => {
+                        rl.add_history_entry(l.as_str());
+                        l
+                    }
+                    Err(ReadlineError::Interrupted) => {
+                        lines.clear();
+                        continue;
+                    }
+                    Err(ReadlineError::Eof) => break,
+                    Err(err) => {
+                        eprintln!("myshell: Error: {:?}", err);
+                        self.last_exit_code = 1;
+                        break;
+                    }
+                }
+            } else {
+                if write_message(&mut control, &Message::Prompt(prompt)).is_err() {
+                    break;
+                }
+                match read_line_fd(STDIN_FILENO) {
+                    Ok(Some(l)) => l,
+                    // client closed the input
+                    Ok(None) => break,
+                    Err(err) => {
+                        eprintln!("myshell: Error: {}", err);
+                        self.last_exit_code = 1;
+                        break;
+                    }
+                }
+            };
+            let input = match lines.push(&line, self.glob_options.extglob) {
+                Some(input) => input,
+                None => continue,
+            };
+            if input.source.trim().is_empty() {
+                continue;
+            }
+            self.last_exit_code = self.interpret_line(input);
+            // output must reach the relay before the status
+            io::stdout().flush().unwrap_or(());
+            if write_message(&mut control, &Message::ExitStatus(self.last_exit_code)).is_err() {
+                break;
+            }
+        }
+        if let Some(history_filename) = history_filename {
+            rl.save_history(&history_filename).unwrap_or(());
+        }
+        self.last_exit_code
+    }
+}
+
+// pid of a connection process, they are reaped by the server
+struct SessionInfo {
+    peer: Peer,
+    started: Instant,
+}
+
+extern "C" fn on_child_exit(_: c_int) {
+    wake_up();
+}
+
+extern "C" fn on_shutdown_request(_: c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+    wake_up();
+}
+
+// called from signal handlers
+fn wake_up() {
+    let fd = WAKEUP_FD.load(Ordering::SeqCst);
+    if fd < 0 {
+        return;
+    }
+    // the interrupted code may be looking at errno
+    let saved = Errno::last();
+    // a full pipe already has a wakeup pending, so a failed write is fine
+    write(fd, &[0]).unwrap_or(0);
+    unsafe { *__errno_location() = saved as i32 };
+}
+
+fn drain_wakeups(wakeup_fd: i32) {
+    let mut chunk = [0u8; 64];
+    while let Ok(n) = read(wakeup_fd, &mut chunk) {
+        if n == 0 {
+            break;
+        }
+    }
+}
+
+// in a process forked by the server
+fn leave_server(server_fds: &[i32]) {
+    WAKEUP_FD.store(-1, Ordering::SeqCst);
+    for sig in [Signal::SIGCHLD, Signal::SIGTERM, Signal::SIGINT] {
+        unsafe { signal(sig, SigHandler::SigDfl) }.unwrap_or(SigHandler::SigDfl);
+    }
+    // ^C on the terminal of the server must not reach the sessions
+    setpgid(Pid::from_raw(0), Pid::from_raw(0)).unwrap_or(());
+    for &fd in server_fds {
+        close(fd).unwrap_or(());
+    }
+}
+
+fn reap_sessions(sessions: &mut HashMap<Pid, SessionInfo>) {
+    loop {
+        let (pid, status) = match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::Exited(pid, code)) => (pid, code),
+            Ok(WaitStatus::Signaled(pid, signal, _)) => (pid, 128 + signal as i32),
+            Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => break,
+            Ok(_) => continue,
+            Err(Errno::EINTR) => continue,
+            Err(err) => {
+                eprintln!("myshell: {}", err);
+                break;
+            }
+        };
+        // rejecting processes are not sessions
+        if let Some(session) = sessions.remove(&pid) {
+            println!(
+                "Session {} for {} finished with status {} after {:.1}s",
+                pid,
+                session.peer,
+                status,
+                session.started.elapsed().as_secs_f64()
+            );
+        }
+    }
+}
+
+fn is_transient_accept_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted | io::ErrorKind::ConnectionAborted
+    )
+}
+
+// the client gets the reason as an Error frame after its hello, a short-lived
+// process waits for it so a slow client does not hold up the accept loop
+fn reject_client(
+    stream: Connection,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    peer: &Peer,
+    reason: &str,
+    server_fds: &[i32],
+) {
+    eprintln!("myshell: rejected {}: {}", peer, reason);
+    match unsafe { fork() } {
+        Ok(Child) => {
+            leave_server(server_fds);
+            let mut stream = match tls {
+                Some(tls) => stream.accept_tls(tls).unwrap_or_else(|_| process::exit(1)),
+                None => stream,
+            };
+            stream.set_read_timeout(Some(REJECT_TIMEOUT)).unwrap_or(());
+            FrameReader::new().read_message(&mut stream).unwrap_or(None);
+            write_message(&mut stream, &Message::Error(reason.to_string())).unwrap_or(());
+            stream.shutdown();
+            process::exit(0);
+        }
+        Ok(Parent { .. }) => {}
+        Err(err) => eprintln!("myshell: fork() failed: {}", err),
+    }
+}
+
+fn authenticate_client(
+    stream: &mut Connection,
+    reader: &mut FrameReader,
+    token: &[u8],
+    report_fd: i32,
+) -> Result<&'static str, String> {
+    let peer = stream.peer()?;
+    match reader.read_message(stream)? {
+        // whoever could connect to the socket file is allowed in
+        Some(Message::Auth(_)) if stream.is_unix() => {
+            println!("Connection from {} authorized by socket permissions", peer);
+            write_message(stream, &Message::AuthOk).map(|_| "socket")
+        }
+        Some(Message::Auth(_)) if stream.has_client_certificate() => {
+            println!("Connection from {} authenticated by client certificate", peer);
+            write_message(stream, &Message::AuthOk).map(|_| "certificate")
+        }
+        Some(Message::Auth(presented)) if tokens_match(&presented, token) => {
+            println!("Connection from {} authenticated", peer);
+            write_message(stream, &Message::AuthOk).map(|_| "token")
+        }
+        Some(Message::Auth(_)) => {
+            if let Some(ip) = peer.ip() {
+                report_auth_failure(report_fd, ip);
+            }
+            thread::sleep(AUTH_FAILURE_DELAY);
+            write_message(stream, &Message::Error("authentication failed".to_string())).unwrap_or(());
+            Err(format!("authentication failed for {}", peer))
+        }
+        Some(_) => Err("protocol error: expected authentication".to_string()),
+        None => Err("connection closed by client".to_string()),
+    }
+}
+
+// stdio of the session process: three pipes, or a pseudo-terminal when the client has one
+enum SessionStdio {
+    Pipes {
+        stdin: (i32, i32),
+        stdout: (i32, i32),
+        stderr: (i32, i32),
+    },
+    Pty {
+        master: i32,
+        slave: i32,
+    },
+}
+
+impl SessionStdio {
+    fn open(tty_size: Option<(u16, u16)>) -> Result<SessionStdio, String> {
+        match tty_size {
+            Some((rows, cols)) => {
+                let size = Winsize {
+                    ws_row: rows,
+                    ws_col: cols,
+                    ws_xpixel: 0,
+                    ws_ypixel: 0,
+                };
+                let pty = openpty(Some(&size), None).map_err(|err| err.to_string())?;
+                // programs spawned by the session must not keep the master open
+                for fd in [pty.master, pty.slave] {
+                    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(|err| err.to_string())?;
+                }
+                Ok(SessionStdio::Pty {
+                    master: pty.master,
+                    slave: pty.slave,
+                })
+            }
+            // close-on-exec, so programs spawned by the session get only the dup2'ed copies
+            None => {
+                let mut pipes = [(0, 0); 3];
+                for pair in &mut pipes {
+                    *pair = pipe2(OFlag::O_CLOEXEC).map_err(|err| err.to_string())?;
+                }
+                let [stdin, stdout, stderr] = pipes;
+                Ok(SessionStdio::Pipes { stdin, stdout, stderr })
+            }
+        }
+    }
+
+    // in the session process
+    fn attach(&self) -> Result<(), String> {
+        let (targets, to_close) = match *self {
+            SessionStdio::Pipes { stdin, stdout, stderr } => (
+                [stdin.0, stdout.1, stderr.1],
+                vec![stdin.0, stdin.1, stdout.0, stdout.1, stderr.0, stderr.1],
+            ),
+            SessionStdio::Pty { master, slave } => {
+                // make the pty the controlling terminal of a new session,
+                // so ^C and window size changes reach the foreground programs
+                setsid().map_err(|err| err.to_string())?;
+                if unsafe { ioctl(slave, TIOCSCTTY as _, 0) } == -1 {
+                    return Err(Errno::last().to_string());
+                }
+                ([slave, slave, slave], vec![master, slave])
+            }
+        };
+        for (fd, target) in targets.into_iter().zip([STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO]) {
+            dup2(fd, target).map_err(|err| err.to_string())?;
+        }
+        for fd in to_close {
+            close(fd).unwrap_or(());
+        }
+        Ok(())
+    }
+
+    // in the relay process: closes the session ends and returns (stdin, stdout, stderr, pty master)
+    fn relay_ends(self) -> (Option<i32>, Option<i32>, Option<i32>, Option<i32>) {
+        match self {
+            SessionStdio::Pipes { stdin, stdout, stderr } => {
+                for fd in [stdin.0, stdout.1, stderr.1] {
+                    close(fd).unwrap_or(());
+                }
+                (Some(stdin.1), Some(stdout.0), Some(stderr.0), None)
+            }
+            SessionStdio::Pty { master, slave } => {
+                close(slave).unwrap_or(());
+                (Some(master), Some(master), None, Some(master))
+            }
+        }
+    }
+}
+
+// moves data between the client connection and the stdio of the session process
+struct SessionRelay {
+    stream: Connection,
+    from_client: FrameReader,
+    session: Pid,
+    // None once closed
+    in_w: Option<i32>,
+    to_session: Vec<u8>,
+    close_stdin: bool,
+    out_r: Option<i32>,
+    err_r: Option<i32>,
+    // pty master, it is both in_w and out_r
+    pty: Option<i32>,
+    // reaches EOF when the session process exits
+    ctl_r: i32,
+    control: FrameReader,
+    idle_timeout: Option<Duration>,
+    // last input from the client or output of the session
+    last_activity: Instant,
+    shutdown_grace: Duration,
+    // set once the server asked the session to wind down
+    shutdown_deadline: Option<Instant>,
+}
+
+impl SessionRelay {
+    fn run(&mut self) -> i32 {
+        for fd in self.stdio_fds() {
+            fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).unwrap_or(0);
+        }
+        let result = self.relay();
+        if let Err(err) = &result {
+            eprintln!("myshell: {}", err);
+            write_message(&mut self.stream, &Message::Error(err.clone())).unwrap_or(());
+            kill(self.session, Signal::SIGHUP).unwrap_or(());
+        }
+        // closing the pty master hangs up the session, so reap it first
+        let status = match waitpid(self.session, None) {
+            Ok(WaitStatus::Exited(_, code)) => code,
+            Ok(WaitStatus::Signaled(_, signal, _)) => 128 + signal as i32,
+            _ => 1,
+        };
+        self.drain_output().unwrap_or(());
+        for fd in self.stdio_fds() {
+            close(fd).unwrap_or(());
+        }
+        close(self.ctl_r).unwrap_or(());
+        if result.is_ok() {
+            write_message(&mut self.stream, &Message::Close(status)).unwrap_or(());
+        }
+        self.stream.shutdown();
+        status
+    }
+
+    // returns once the session process has exited
+    fn relay(&mut self) -> Result<(), String> {
+        // input may have arrived together with the hello
+        self.handle_client_messages()?;
+        loop {
+            // TLS may have decrypted more than the handshake consumed
+            if self.stream.has_buffered_input() {
+                self.read_from_client()?;
+            }
+            let timeout = self.check_deadlines()?;
+            let mut watched: Vec<(i32, PollFlags)> = vec![
+                (self.stream.as_raw_fd(), PollFlags::POLLIN),
+                (self.ctl_r, PollFlags::POLLIN),
+            ];
+            for fd in [self.out_r, self.err_r].into_iter().flatten() {
+                watched.push((fd, PollFlags::POLLIN));
+            }
+            if let Some(fd) = self.in_w {
+                if !self.to_session.is_empty() {
+                    watched.push((fd, PollFlags::POLLOUT));
+                }
+            }
+            let mut poll_fds: Vec<PollFd> = watched.iter().map(|&(fd, flags)| PollFd::new(fd, flags)).collect();
+            match poll(&mut poll_fds, timeout) {
+                Ok(_) => {}
+                Err(Errno::EINTR) => continue,
+                Err(err) => return Err(err.to_string()),
+            }
+            let ready: Vec<(i32, PollFlags)> = watched
+                .into_iter()
+                .zip(&poll_fds)
+                .filter(|(_, pfd)| pfd.revents().is_some_and(|r| !r.is_empty()))
+                .map(|(watch, _)| watch)
+                .collect();
+
+            for (fd, flags) in ready {
+                if flags == PollFlags::POLLOUT {
+                    self.write_to_session()?;
+                } else if fd == self.stream.as_raw_fd() {
+                    self.read_from_client()?;
+                } else if Some(fd) == self.out_r || Some(fd) == self.err_r {
+                    self.forward_output(fd)?;
+                } else if fd == self.ctl_r {
+                    // everything the session printed before a status goes first
+                    self.drain_output()?;
+                    let mut chunk = [0u8; 4096];
+                    let n = match read(self.ctl_r, &mut chunk) {
+                        Ok(n) => n,
+                        Err(Errno::EINTR) => continue,
+                        Err(err) => return Err(err.to_string()),
+                    };
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    self.control.push(&chunk[..n]);
+                    while let Some(message) = self.control.next_message()? {
+                        write_message(&mut self.stream, &message)?;
+                    }
+                }
+            }
+        }
+    }
+
+    fn read_from_client(&mut self) -> Result<(), String> {
+        match self.stream.read_available().map_err(|err| err.to_string())? {
+            Some(data) => {
+                self.last_activity = Instant::now();
+                self.from_client.push(&data);
+                self.handle_client_messages()
+            }
+            None => Err("connection closed by client".to_string()),
+        }
+    }
+
+    // returns the poll timeout in milliseconds until the nearest deadline
+    fn check_deadlines(&mut self) -> Result<i32, String> {
+        let now = Instant::now();
+        if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) && self.shutdown_deadline.is_none() {
+            self.shutdown_deadline = Some(now + self.shutdown_grace);
+            write_message(&mut self.stream, &Message::Shutdown(self.shutdown_grace.as_secs() as u32))?;
+        }
+        if self.shutdown_deadline.is_some_and(|deadline| deadline <= now) {
+            return Err("server shut down".to_string());
+        }
+        let idle_deadline = self.idle_timeout.map(|timeout| self.last_activity + timeout);
+        if idle_deadline.is_some_and(|deadline| deadline <= now) {
+            return Err(format!(
+                "session closed after {}s without activity",
+                self.idle_timeout.unwrap_or_default().as_secs()
+            ));
+        }
+        Ok(match [idle_deadline, self.shutdown_deadline].into_iter().flatten().min() {
+            Some(deadline) => deadline.saturating_duration_since(now).as_millis() as i32 + 1,
+            None => -1,
+        })
+    }
+
+    fn handle_client_messages(&mut self) -> Result<(), String> {
+        while let Some(message) = self.from_client.next_message()? {
+            match message {
+                Message::Input(data) => self.to_session.extend_from_slice(&data),
+                // a terminal has no end of input, hang up instead
+                Message::Close(_) if self.pty.is_some() => {
+                    kill(self.session, Signal::SIGHUP).unwrap_or(());
+                }
+                Message::Close(_) => self.close_stdin = true,
+                Message::WindowSize(rows, cols) => {
+                    if let Some(master) = self.pty {
+                        let size = Winsize {
+                            ws_row: rows,
+                            ws_col: cols,
+                            ws_xpixel: 0,
+                            ws_ypixel: 0,
+                        };
+                        // the kernel sends SIGWINCH to the foreground programs
+                        unsafe { ioctl(master, TIOCSWINSZ, &size) };
+                    }
+                }
+                _ => return Err("protocol error: unexpected message from client".to_string()),
+            }
+        }
+        self.write_to_session()
+    }
+
+    fn write_to_session(&mut self) -> Result<(), String> {
+        let fd = match self.in_w {
+            Some(fd) => fd,
+            None => return Ok(()),
+        };
+        while !self.to_session.is_empty() {
+            match write(fd, &self.to_session) {
+                Ok(n) => {
+                    self.to_session.drain(..n);
                 }
+                Err(Errno::EAGAIN) => return Ok(()),
+                Err(Errno::EINTR) => continue,
+                // the session does not read its stdin anymore
+                Err(Errno::EPIPE | Errno::EIO) => {
+                    self.to_session.clear();
+                    break;
+                }
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+        if self.close_stdin {
+            close(fd).unwrap_or(());
+            self.in_w = None;
+        }
+        Ok(())
+    }
+
+    // sends one chunk of the session stdout or stderr, returns false when nothing was read
+    fn forward_output(&mut self, fd: i32) -> Result<bool, String> {
+        let mut chunk = [0u8; 4096];
+        let n = match read(fd, &mut chunk) {
+            Ok(n) => n,
+            Err(Errno::EAGAIN | Errno::EINTR) => return Ok(false),
+            // pty master after the last slave descriptor is closed
+            Err(Errno::EIO) if self.pty == Some(fd) => 0,
+            Err(err) => return Err(err.to_string()),
+        };
+        if n == 0 {
+            if self.pty != Some(fd) {
+                close(fd).unwrap_or(());
             }
+            if self.out_r == Some(fd) {
+                self.out_r = None;
+            } else {
+                self.err_r = None;
+            }
+            return Ok(false);
         }
-        return 0;
+        self.last_activity = Instant::now();
+        let data = chunk[..n].to_vec();
+        let message = if self.out_r == Some(fd) {
+            Message::Stdout(data)
+        } else {
+            Message::Stderr(data)
+        };
+        write_message(&mut self.stream, &message)?;
+        Ok(true)
     }
 
-    pub fn start_remote_interpreter(mut client_stream: TcpStream) -> i32 {
-        client_stream.as_raw_fd();
-        return 0;
+    fn stdio_fds(&self) -> Vec<i32> {
+        let mut fds: Vec<i32> = [self.in_w, self.out_r, self.err_r].into_iter().flatten().collect();
+        fds.dedup();
+        fds
+    }
+
+    fn drain_output(&mut self) -> Result<(), String> {
+        for fd in [self.out_r, self.err_r].into_iter().flatten() {
+            while self.forward_output(fd)? {}
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,253 @@
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use std::sync::Arc;
+
+// PEM files of a certificate chain and its private key
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub cert: String,
+    pub key: String,
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("{}: {}", path, err))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates found", path));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    PrivateKeyDer::from_pem_file(path).map_err(|err| format!("{}: {}", path, err))
+}
+
+fn load_roots(path: &str) -> Result<Arc<RootCertStore>, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(|err| format!("{}: {}", path, err))?;
+    }
+    Ok(Arc::new(roots))
+}
+
+// Trusts the certificates of the CA file themselves besides the ones they
+// sign, so a self-signed server certificate can be passed as the CA. webpki
+// alone rejects it when it is marked as a CA.
+#[derive(Debug)]
+struct PinningVerifier {
+    pinned: Vec<CertificateDer<'static>>,
+    webpki: Arc<WebPkiServerVerifier>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.pinned.iter().any(|cert| cert.as_ref() == end_entity.as_ref()) {
+            return Ok(ServerCertVerified::assertion());
+        }
+        self.webpki.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.webpki.supported_verify_schemes()
+    }
+}
+
+// With `client_ca` clients with a certificate signed by it need no token,
+// the others still get through the handshake and authenticate with the token.
+pub fn server_config(identity: &Identity, client_ca: Option<&str>) -> Result<Arc<ServerConfig>, String> {
+    let builder = ServerConfig::builder();
+    let builder = match client_ca {
+        Some(path) => {
+            let verifier = WebPkiClientVerifier::builder(load_roots(path)?)
+                .allow_unauthenticated()
+                .build()
+                .map_err(|err| format!("{}: {}", path, err))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(load_certs(&identity.cert)?, load_key(&identity.key)?)
+        .map_err(|err| format!("{}: {}", identity.cert, err))?;
+    Ok(Arc::new(config))
+}
+
+pub fn client_config(ca: &str, identity: Option<&Identity>) -> Result<Arc<ClientConfig>, String> {
+    let webpki = WebPkiServerVerifier::builder(load_roots(ca)?)
+        .build()
+        .map_err(|err| format!("{}: {}", ca, err))?;
+    let verifier = PinningVerifier { pinned: load_certs(ca)?, webpki };
+    let builder = ClientConfig::builder().dangerous().with_custom_certificate_verifier(Arc::new(verifier));
+    let config = match identity {
+        Some(identity) => builder
+            .with_client_auth_cert(load_certs(&identity.cert)?, load_key(&identity.key)?)
+            .map_err(|err| format!("{}: {}", identity.cert, err))?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+// the name the server certificate has to be issued for: the host of host:port
+pub fn server_name(address: &str) -> Result<ServerName<'static>, String> {
+    let host = match address.rsplit_once(':') {
+        Some((host, _)) => host,
+        None => address,
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_string()).map_err(|_| format!("invalid server name '{}'", host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use rustls::{ClientConnection, Connection, ServerConnection};
+    use std::fs;
+    use std::path::PathBuf;
+
+    struct Files {
+        dir: PathBuf,
+    }
+
+    impl Files {
+        fn new(test: &str) -> Files {
+            let dir = std::env::temp_dir().join(format!("myshell-tls-{}-{}", std::process::id(), test));
+            fs::create_dir_all(&dir).unwrap();
+            Files { dir }
+        }
+
+        fn write(&self, name: &str, contents: &str) -> String {
+            let path = self.dir.join(name);
+            fs::write(&path, contents).unwrap();
+            path.to_string_lossy().into_owned()
+        }
+
+        // a certificate for localhost, self-signed or signed by `issuer`
+        fn cert(&self, name: &str, ca: bool, issuer: Option<&(rcgen::Certificate, KeyPair)>) -> (rcgen::Certificate, KeyPair, Identity) {
+            let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+            if ca {
+                params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            } else {
+                params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth, ExtendedKeyUsagePurpose::ClientAuth];
+            }
+            let key = KeyPair::generate().unwrap();
+            let cert = match issuer {
+                Some((issuer, issuer_key)) => params.signed_by(&key, issuer, issuer_key).unwrap(),
+                None => params.self_signed(&key).unwrap(),
+            };
+            let identity = Identity {
+                cert: self.write(&format!("{}.pem", name), &cert.pem()),
+                key: self.write(&format!("{}.key", name), &key.serialize_pem()),
+            };
+            (cert, key, identity)
+        }
+    }
+
+    impl Drop for Files {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.dir).unwrap_or(());
+        }
+    }
+
+    // moves what `from` has to send to `to`, false when there was nothing
+    fn transfer(from: &mut Connection, to: &mut Connection) -> Result<bool, rustls::Error> {
+        let mut buffer = Vec::new();
+        while from.wants_write() {
+            from.write_tls(&mut buffer).unwrap();
+        }
+        if buffer.is_empty() {
+            return Ok(false);
+        }
+        to.read_tls(&mut buffer.as_slice()).unwrap();
+        to.process_new_packets()?;
+        Ok(true)
+    }
+
+    // runs the handshake in memory, returns the server side when it succeeds
+    fn handshake(client: Arc<ClientConfig>, server: Arc<ServerConfig>) -> Result<ServerConnection, rustls::Error> {
+        let name = server_name("localhost:8022").unwrap();
+        let mut client = Connection::Client(ClientConnection::new(client, name)?);
+        let mut server = Connection::Server(ServerConnection::new(server)?);
+        while client.is_handshaking() || server.is_handshaking() {
+            let sent = transfer(&mut client, &mut server)?;
+            if !transfer(&mut server, &mut client)? && !sent {
+                break;
+            }
+        }
+        match server {
+            Connection::Server(server) => Ok(server),
+            Connection::Client(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn self_signed_server_certificate_is_its_own_ca() {
+        let files = Files::new("self-signed");
+        // like `openssl req -x509`, which marks the certificate as a CA
+        let (_, _, server) = files.cert("server", true, None);
+        let client = client_config(&server.cert, None).unwrap();
+        assert!(handshake(client, server_config(&server, None).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn unknown_ca_is_rejected() {
+        let files = Files::new("unknown-ca");
+        let ca = files.cert("ca", true, None);
+        let (_, _, server) = files.cert("server", false, Some(&(ca.0, ca.1)));
+        let (_, _, other) = files.cert("other", true, None);
+        let client = client_config(&other.cert, None).unwrap();
+        assert!(handshake(client, server_config(&server, None).unwrap()).is_err());
+    }
+
+    #[test]
+    fn client_certificate_signed_by_ca_is_accepted() {
+        let files = Files::new("client-cert");
+        let (ca_cert, ca_key, ca) = files.cert("ca", true, None);
+        let issuer = (ca_cert, ca_key);
+        let (_, _, server) = files.cert("server", false, Some(&issuer));
+        let (_, _, client) = files.cert("client", false, Some(&issuer));
+        let config = client_config(&ca.cert, Some(&client)).unwrap();
+        let server = handshake(config, server_config(&server, Some(&ca.cert)).unwrap()).unwrap();
+        assert!(server.peer_certificates().is_some());
+    }
+
+    #[test]
+    fn client_without_certificate_gets_through() {
+        let files = Files::new("no-client-cert");
+        let (ca_cert, ca_key, ca) = files.cert("ca", true, None);
+        let (_, _, server) = files.cert("server", false, Some(&(ca_cert, ca_key)));
+        let config = client_config(&ca.cert, None).unwrap();
+        let server = handshake(config, server_config(&server, Some(&ca.cert)).unwrap()).unwrap();
+        assert!(server.peer_certificates().is_none());
+    }
+}
@@ -1,32 +1,99 @@
 use libc::{STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO};
-use nix::libc::dup;
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{read, Pid};
 use std::fs::File;
 use std::os::unix::io::FromRawFd;
 use std::io::{Write, Result as IoResult};
 use std::path::{PathBuf};
 use std::process;
+use std::env;
+use rustyline::Editor;
 
+// copies of the shell's own descriptors are close-on-exec and out of the range
+// commands can redirect
 pub unsafe fn ioe_descriptors_to_files(descs: &[i32; 3]) -> (File, File, File) {
-    let in_ = if descs[0] == STDIN_FILENO { dup(STDIN_FILENO) } else  { descs[0] };
-    let out_ = if descs[1] == STDOUT_FILENO { dup(STDOUT_FILENO) } else  { descs[1] };
-    let err_ = if descs[2] == STDERR_FILENO { dup(STDERR_FILENO) } else  { descs[2] };
-    return (
+    let copy = |fd| fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(10)).unwrap_or(-1);
+    let in_ = if descs[0] == STDIN_FILENO { copy(STDIN_FILENO) } else  { descs[0] };
+    let out_ = if descs[1] == STDOUT_FILENO { copy(STDOUT_FILENO) } else  { descs[1] };
+    let err_ = if descs[2] == STDERR_FILENO { copy(STDERR_FILENO) } else  { descs[2] };
+    (
         File::from_raw_fd(in_),
         File::from_raw_fd(out_),
         File::from_raw_fd(err_),
-    );
+    )
 }
 
 pub fn writex(mut f: &File, message: &str) {
     write!(f, "{}", message).expect("Failed to write in the internal command");
 }
 
+// returns path to the history file, creating it if needed
+pub fn load_history(rl: &mut Editor<()>) -> String {
+    let home_path = env::var("HOME").unwrap_or_else(|_| {
+        eprintln!("Error: HOME variable is unset");
+        process::exit(1);
+    });
+    let history_filename = home_path + "/.myshell_history";
+
+    if rl.load_history(&history_filename).is_err() {
+        File::create(&history_filename).unwrap_or_else(|err| {
+            eprintln!("Error: could not create history file: {}", err);
+            process::exit(1);
+        });
+    }
+    history_filename
+}
+
 pub fn result_pathbuf_to_string(res: IoResult<PathBuf>) -> String {
-    return res.unwrap_or_else(|error| {
+    res.unwrap_or_else(|error| {
         eprintln!(
             "myshell: Error: could not determine path to the executable: {}",
             error
         );
         process::exit(1);
-    }).into_os_string().into_string().unwrap();
-}
\ No newline at end of file
+    }).into_os_string().into_string().unwrap()
+}
+
+// reads byte by byte, so nothing after the newline is consumed
+// and child processes that share the descriptor still get their input
+pub fn read_line_fd(fd: i32) -> IoResult<Option<String>> {
+    let mut line: Vec<u8> = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        match read(fd, &mut byte) {
+            Ok(0) => {
+                if line.is_empty() {
+                    return Ok(None);
+                }
+                break;
+            }
+            Ok(_) => {
+                if byte[0] == b'\n' {
+                    break;
+                }
+                line.push(byte[0]);
+            }
+            Err(Errno::EINTR) => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    // telnet-like clients terminate lines with \r\n
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+// exit status of a forked child, 128 + signal number when it was killed
+pub fn wait_for(child: Pid) -> Result<i32, String> {
+    loop {
+        match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, code)) => return Ok(code),
+            Ok(WaitStatus::Signaled(_, signal, _)) => return Ok(128 + signal as i32),
+            Err(Errno::EINTR) | Ok(_) => continue,
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+}